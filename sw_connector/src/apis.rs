@@ -0,0 +1,41 @@
+use crate::metrics::{self, CONNECTION_STATE};
+use actix_web::dev::Server;
+use actix_web::{get, middleware::Logger, App, HttpResponse, HttpServer, Responder};
+use log::info;
+use serde_json::json;
+use std::io;
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+  let state = CONNECTION_STATE.read().await.clone();
+  let body = json!({
+    "connected": state.connected,
+    "since": state.since,
+    "listener": state.listener,
+  });
+  if state.connected {
+    HttpResponse::Ok().json(body)
+  } else {
+    HttpResponse::ServiceUnavailable().json(body)
+  }
+}
+
+#[get("/metrics")]
+async fn metrics_handler() -> impl Responder {
+  match metrics::gather() {
+    Ok(body) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body),
+    Err(e) => HttpResponse::InternalServerError().body(format!("Failed to gather metrics: {}", e)),
+  }
+}
+
+// Function to bind the health endpoint, the returned server runs once it is polled
+//
+// actix signal handling is disabled so that SIGTERM and Ctrl-C stop sw_connector instead of only this server
+pub fn create_app(addr: &str) -> io::Result<Server> {
+  let app = move || App::new().wrap(Logger::default()).service(healthz).service(metrics_handler);
+  let server = HttpServer::new(app).workers(1).disable_signals().bind(addr).map_err(|e| {
+    io::Error::new(e.kind(), format!("Failed to bind health endpoint to {}: {}", addr, e))
+  })?;
+  info!("Health endpoint listening on {}", addr);
+  Ok(server.run())
+}
@@ -1,2 +1,8 @@
+pub mod apis;
 pub mod utils;
-pub mod quic;
\ No newline at end of file
+pub mod metrics;
+pub mod policy;
+pub mod quic;
+pub mod reconnect;
+pub mod reverse;
+pub mod udp;
\ No newline at end of file
@@ -1,8 +1,8 @@
-use log::{error, info};
+use log::{error, info, warn};
 use quinn::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
 use quinn_proto::crypto::rustls::QuicClientConfig;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
   env,
   error::Error,
@@ -11,7 +11,12 @@ use std::{
   net::ToSocketAddrs,
   sync::Arc,
 };
-use swc_lib::quic::{handle_stream, ALPN_QUIC_HTTP};
+use swc_lib::apis::create_app;
+use swc_lib::metrics::{set_connected, RECONNECTS};
+use swc_lib::policy::{Policy, PolicyConfig};
+use swc_lib::quic::{handle_stream, StreamOptions, ALPN_QUIC_HTTP};
+use swc_lib::reconnect::{Backoff, ReconnectConfig};
+use swc_lib::reverse::{set_connection, start_reverse_listeners, ReverseConfig};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Config {
@@ -20,11 +25,28 @@ struct Config {
   ca_cert_path: String,
   server_name: String,
   service_port: u16,
+  #[serde(default)]
+  reconnect: ReconnectConfig,
+  #[serde(default)]
+  policy: PolicyConfig,
+  #[serde(default)]
+  metrics_address: Option<String>,
+  #[serde(default)]
+  reverse: Vec<ReverseConfig>,
+  #[serde(default = "default_udp_idle_timeout_secs")]
+  udp_idle_timeout_secs: u64,
+  #[serde(default)]
+  idle_timeout_secs: Option<u64>,
+  #[serde(default)]
+  max_lifetime_secs: Option<u64>,
+}
+
+fn default_udp_idle_timeout_secs() -> u64 {
+  60
 }
 
 const KEEP_ALIVE_INTERVAL_SECS: u64 = 50;
 const MAX_IDLE_TIMEOUT_SECS: u64 = 60;
-const MAX_VECTOR_SIZE: usize = 1024;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -34,23 +56,74 @@ async fn main() -> Result<(), Box<dyn Error>> {
   let config = load_config("settings.json")?;
   let (certs, key) = load_client_cert_and_key(&config)?;
   let client_auth_roots = load_ca_cert(&config)?;
+  let options = Arc::new(StreamOptions {
+    policy: Policy::new(&config.policy)?,
+    udp_idle_timeout: Duration::from_secs(config.udp_idle_timeout_secs),
+    idle_timeout: config.idle_timeout_secs.map(Duration::from_secs),
+    max_lifetime: config.max_lifetime_secs.map(Duration::from_secs),
+  });
 
   let client_config = configure_client(certs, key, client_auth_roots)?;
   let mut endpoint = quinn::Endpoint::client("[::]:0".parse().unwrap())?;
   endpoint.set_default_client_config(client_config);
 
-  let server_addrs = resolve_server_address(&config)?;
+  if let Some(metrics_address) = &config.metrics_address {
+    let server = create_app(metrics_address)?;
+    tokio::spawn(async move {
+      if let Err(e) = server.await {
+        error!("Health endpoint failed: {}", e);
+      }
+    });
+  }
+
+  start_reverse_listeners(&config.reverse).await?;
+
   let host = config.server_name.clone();
+  let mut backoff = Backoff::new(config.reconnect.clone());
 
-  info!("QUIC connecting to {} at {}", server_addrs, host);
-  let connection = endpoint.connect(server_addrs, &host)?.await?;
-  info!("QUIC connected");
+  loop {
+    match connect(&endpoint, &config, &host).await {
+      Ok(connection) => {
+        info!("QUIC connected");
+        let connected_at = Instant::now();
+        let listener = connection.remote_address().to_string();
+        set_connected(true, &listener).await;
+        set_connection(Some(connection.clone())).await;
+
+        info!("Starting to wait for QUIC streams");
+        if let Err(e) = wait_for_quic_stream(connection, options.clone()).await {
+          error!("QUIC connection lost: {}", e);
+        }
+        info!("Finished waiting for QUIC streams");
+        set_connection(None).await;
+        set_connected(false, &listener).await;
+        if !backoff.reset_if_stable(connected_at.elapsed()) {
+          warn!("QUIC connection closed after {:?}, counting it as a failed attempt", connected_at.elapsed());
+        }
+      }
+      Err(e) => {
+        error!("QUIC connection failed: {}", e);
+      }
+    }
 
-  info!("Starting to wait for QUIC streams");
-  wait_for_quic_stream(connection).await?;
-  info!("Finished waiting for QUIC streams");
+    let delay = match backoff.next_delay() {
+      Some(delay) => delay,
+      None => {
+        error!("Giving up after {} reconnect attempts", backoff.attempt());
+        return Err("Reconnect attempts exhausted".into());
+      }
+    };
+    info!("Reconnecting in {} ms (attempt {})", delay.as_millis(), backoff.attempt());
+    RECONNECTS.inc();
+    tokio::time::sleep(delay).await;
+  }
+}
 
-  Ok(())
+async fn connect(endpoint: &quinn::Endpoint, config: &Config, host: &str) -> Result<quinn::Connection, Box<dyn Error>> {
+  let server_addrs = resolve_server_address(config)?;
+  info!("QUIC connecting to {} at {}", server_addrs, host);
+  let connection = endpoint.connect(server_addrs, host)?.await?;
+  Ok(connection)
 }
 
 fn load_config(file_path: &str) -> Result<Config, Box<dyn Error>> {
@@ -101,15 +174,18 @@ fn resolve_server_address(config: &Config) -> Result<std::net::SocketAddr, Box<d
   let server_addrs = (config.server_name.clone(), config.service_port)
     .to_socket_addrs()?
     .next()
-    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to resolve address"))?;
+    .ok_or_else(|| io::Error::other("Failed to resolve address"))?;
   Ok(server_addrs)
 }
 
-async fn wait_for_quic_stream(connection: quinn::Connection) -> Result<(), Box<dyn Error>> {
+async fn wait_for_quic_stream(
+  connection: quinn::Connection,
+  options: Arc<StreamOptions>,
+) -> Result<(), Box<dyn Error>> {
   loop {
     let stream = match connection.accept_bi().await {
-      Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-        info!("connection closed by application");
+      Err(quinn::ConnectionError::ApplicationClosed(close)) => {
+        info!("connection closed by application: {}", close);
         return Ok(());
       }
       Err(e) => {
@@ -118,9 +194,10 @@ async fn wait_for_quic_stream(connection: quinn::Connection) -> Result<(), Box<d
       }
       Ok(s) => s,
     };
+    let options = options.clone();
     tokio::spawn(async move {
-      if let Err(e) = handle_stream(stream, MAX_VECTOR_SIZE).await {
-        error!("failed: {reason}", reason = e.to_string());
+      if let Err(e) = handle_stream(stream, options).await {
+        error!("failed: {reason}", reason = e);
       }
     });
   }
@@ -0,0 +1,64 @@
+use lazy_static::lazy_static;
+use prometheus::{
+  register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter, IntCounterVec, IntGauge,
+  TextEncoder,
+};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionState {
+  pub connected: bool,
+  pub since: u64,
+  pub listener: String,
+}
+
+lazy_static! {
+  pub static ref CONNECTION_STATE: Arc<RwLock<ConnectionState>> = Arc::new(RwLock::new(ConnectionState::default()));
+  pub static ref CONNECTED: IntGauge =
+    register_int_gauge!("swc_connected", "Whether the QUIC connection to sw_listener is up").unwrap();
+  pub static ref STREAMS_HANDLED: IntCounter =
+    register_int_counter!("swc_streams_total", "Streams opened by sw_listener").unwrap();
+  pub static ref DIAL_FAILURES: IntCounterVec = register_int_counter_vec!(
+    "swc_dial_failures_total",
+    "Failures to connect to an edge server",
+    &["destination", "status"]
+  )
+  .unwrap();
+  pub static ref UDP_FLOWS: IntGauge = register_int_gauge!("swc_udp_flows", "Number of active UDP flows").unwrap();
+  pub static ref BYTES_COPIED: IntCounterVec = register_int_counter_vec!(
+    "swc_bytes_copied_total",
+    "Bytes copied between sw_listener and edge servers",
+    &["direction"]
+  )
+  .unwrap();
+  pub static ref REVERSE_ACCEPTED: IntCounterVec = register_int_counter_vec!(
+    "swc_reverse_accepted_total",
+    "Connections accepted on reverse ports",
+    &["listen_address"]
+  )
+  .unwrap();
+  pub static ref SESSION_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+    "swc_session_timeouts_total",
+    "Sessions closed by the idle timeout or the max lifetime",
+    &["reason"]
+  )
+  .unwrap();
+  pub static ref RECONNECTS: IntCounter =
+    register_int_counter!("swc_reconnects_total", "Reconnect attempts to sw_listener").unwrap();
+}
+
+// Function to record a change of the QUIC connection state
+pub async fn set_connected(connected: bool, listener: &str) {
+  let since = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let mut state = CONNECTION_STATE.write().await;
+  *state = ConnectionState { connected, since, listener: listener.to_string() };
+  CONNECTED.set(connected as i64);
+}
+
+pub fn gather() -> Result<String, Box<dyn std::error::Error>> {
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+  Ok(String::from_utf8(buffer)?)
+}
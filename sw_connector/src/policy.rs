@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+use swp_lib::pattern::{parse_port_range, HostPattern};
+
+// Destination policy read from the "policy" section of settings.json
+//
+// A destination is refused when it matches any deny rule. When allow rules are given,
+// it must also match at least one of them. Without a policy every destination is allowed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PolicyConfig {
+  pub allow: Vec<RuleConfig>,
+  pub deny: Vec<RuleConfig>,
+}
+
+// hosts accepts CIDRs, IP addresses and host names with "*" wildcards, ports accepts numbers and "from-to" ranges.
+// An empty list matches anything.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+  pub hosts: Vec<String>,
+  pub ports: Vec<PortConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum PortConfig {
+  Single(u16),
+  Range(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+  allow: Vec<Rule>,
+  deny: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+  hosts: Vec<HostPattern>,
+  ports: Vec<(u16, u16)>,
+}
+
+impl Policy {
+  pub fn new(config: &PolicyConfig) -> Result<Self, Box<dyn Error>> {
+    Ok(Policy {
+      allow: config.allow.iter().map(Rule::new).collect::<Result<_, _>>()?,
+      deny: config.deny.iter().map(Rule::new).collect::<Result<_, _>>()?,
+    })
+  }
+
+  // Function to check a destination and its resolved addresses, returning the reason when it is refused
+  pub fn check(&self, host: &str, addrs: &[SocketAddr], port: u16) -> Result<(), String> {
+    if self.deny.iter().any(|rule| rule.matches_any(host, addrs, port)) {
+      return Err(format!("{}:{} matches a deny rule", host, port));
+    }
+    if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches_all(host, addrs, port)) {
+      return Err(format!("{}:{} does not match any allow rule", host, port));
+    }
+    Ok(())
+  }
+}
+
+impl Rule {
+  fn new(config: &RuleConfig) -> Result<Self, Box<dyn Error>> {
+    let hosts = config
+      .hosts
+      .iter()
+      .map(|host| HostPattern::parse(host).map_err(|e| format!("{} in policy", e)))
+      .collect::<Result<_, _>>()?;
+    let ports = config.ports.iter().map(parse_ports).collect::<Result<_, _>>()?;
+    Ok(Rule { hosts, ports })
+  }
+
+  fn is_any_host(&self) -> bool {
+    self.hosts.is_empty()
+  }
+
+  fn matches_port(&self, port: u16) -> bool {
+    self.ports.is_empty() || self.ports.iter().any(|&(from, to)| from <= port && port <= to)
+  }
+
+  fn matches_name(&self, host: &str) -> bool {
+    self.hosts.iter().any(|pattern| pattern.matches_name(host))
+  }
+
+  fn contains(&self, addr: &SocketAddr) -> bool {
+    self.hosts.iter().any(|pattern| pattern.contains(&addr.ip()))
+  }
+
+  // Used for deny rules: any resolved address inside a network is enough
+  fn matches_any(&self, host: &str, addrs: &[SocketAddr], port: u16) -> bool {
+    self.matches_port(port)
+      && (self.is_any_host() || self.matches_name(host) || addrs.iter().any(|addr| self.contains(addr)))
+  }
+
+  // Used for allow rules: every resolved address must be inside a network
+  fn matches_all(&self, host: &str, addrs: &[SocketAddr], port: u16) -> bool {
+    self.matches_port(port)
+      && (self.is_any_host()
+        || self.matches_name(host)
+        || (!addrs.is_empty() && addrs.iter().all(|addr| self.contains(addr))))
+  }
+}
+
+fn parse_ports(config: &PortConfig) -> Result<(u16, u16), Box<dyn Error>> {
+  match config {
+    PortConfig::Single(port) => Ok((*port, *port)),
+    PortConfig::Range(range) => Ok(parse_port_range(range).map_err(|e| format!("{} in policy", e))?),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(config: serde_json::Value) -> Policy {
+    Policy::new(&serde_json::from_value(config).unwrap()).unwrap()
+  }
+
+  fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+    ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 0)).collect()
+  }
+
+  #[test]
+  fn empty_policy_allows_everything() {
+    assert!(Policy::default().check("example.com", &addrs(&["192.0.2.1"]), 22).is_ok());
+  }
+
+  #[test]
+  fn deny_beats_allow() {
+    let policy = policy(serde_json::json!({
+      "allow": [{ "hosts": ["10.0.0.0/8"] }],
+      "deny": [{ "hosts": ["10.0.0.5"], "ports": [22] }]
+    }));
+    assert!(policy.check("10.0.0.5", &addrs(&["10.0.0.5"]), 80).is_ok());
+    let reason = policy.check("10.0.0.5", &addrs(&["10.0.0.5"]), 22).unwrap_err();
+    assert!(reason.contains("deny"), "{}", reason);
+    assert!(policy.check("192.0.2.1", &addrs(&["192.0.2.1"]), 80).is_err());
+  }
+
+  #[test]
+  fn deny_matches_any_address_allow_needs_all() {
+    let allow = policy(serde_json::json!({ "allow": [{ "hosts": ["10.0.0.0/8"] }] }));
+    assert!(allow.check("internal", &addrs(&["10.0.0.1", "10.0.0.2"]), 80).is_ok());
+    assert!(allow.check("mixed", &addrs(&["10.0.0.1", "192.0.2.1"]), 80).is_err());
+
+    let deny = policy(serde_json::json!({ "deny": [{ "hosts": ["169.254.0.0/16"] }] }));
+    assert!(deny.check("evil", &addrs(&["192.0.2.1", "169.254.169.254"]), 80).is_err());
+  }
+
+  #[test]
+  fn wildcard_host_names() {
+    let policy = policy(serde_json::json!({ "allow": [{ "hosts": ["*.example.com", "api-*.internal"] }] }));
+    assert!(policy.check("www.Example.com", &[], 443).is_ok());
+    assert!(policy.check("api-v2.internal", &[], 443).is_ok());
+    assert!(policy.check("example.com", &[], 443).is_err());
+    assert!(policy.check("www.example.com.evil", &[], 443).is_err());
+  }
+
+  #[test]
+  fn port_ranges() {
+    let policy = policy(serde_json::json!({ "allow": [{ "ports": [443, "8000-8099"] }] }));
+    for port in [443, 8000, 8050, 8099] {
+      assert!(policy.check("example.com", &[], port).is_ok(), "{}", port);
+    }
+    for port in [80, 7999, 8100] {
+      assert!(policy.check("example.com", &[], port).is_err(), "{}", port);
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_rules() {
+    let invalid = |config: serde_json::Value| Policy::new(&serde_json::from_value(config).unwrap()).is_err();
+    assert!(invalid(serde_json::json!({ "deny": [{ "hosts": ["10.0.0.0/33"] }] })));
+    assert!(invalid(serde_json::json!({ "deny": [{ "ports": ["90-80"] }] })));
+    assert!(invalid(serde_json::json!({ "deny": [{ "ports": ["http"] }] })));
+  }
+}
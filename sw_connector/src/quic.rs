@@ -1,34 +1,81 @@
+use crate::metrics::{BYTES_COPIED, DIAL_FAILURES, SESSION_TIMEOUTS, STREAMS_HANDLED};
+use crate::policy::Policy;
+use crate::udp::handle_udp_flow;
 use log::{error, info, warn};
 use std::error::Error;
-use tokio::net::TcpStream;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use swp_lib::copy::{self, CopyEnd, CopyLimits, Direction};
+use swp_lib::header::{
+  StreamHeader, FLAG_PING, FLAG_UDP, TLV_DESTINATION_ADDRESS, TLV_PROXY_PROTOCOL, TLV_SOURCE_ADDRESS,
+};
+use swp_lib::proxy_protocol;
+use swp_lib::status::{ConnectStatus, StatusFrame};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
 
 pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Default)]
+pub struct StreamOptions {
+  pub policy: Policy,
+  pub udp_idle_timeout: Duration,
+  pub idle_timeout: Option<Duration>,
+  pub max_lifetime: Option<Duration>,
+}
 
 pub async fn handle_stream(
   (mut send, mut recv): (quinn::SendStream, quinn::RecvStream),
-  max_vector_size: usize,
+  options: Arc<StreamOptions>,
 ) -> Result<(), Box<dyn Error>> {
   info!("new stream opened from agent");
+  STREAMS_HANDLED.inc();
 
   // receive address
-  let (id, edge_server_addr) = receive_address(&mut recv, max_vector_size).await?;
-  info!(
-    "{} |Received edge server address from sw_listener: {}",
-    id, edge_server_addr
-  );
+  let header = StreamHeader::read_from(&mut recv).await?;
+  let id = header.id.clone();
+  if header.has_flag(FLAG_PING) {
+    info!("{} | Received ping from sw_listener", id);
+    send_status(&mut send, &StatusFrame::new(ConnectStatus::Success, "")).await?;
+    send.finish()?;
+    return Ok(());
+  }
+  if header.host.is_empty() {
+    return Err("Invalid address format".into());
+  }
+  info!("{} |Received edge server address from sw_listener: {}", id, header.target());
+  if header.has_flag(FLAG_UDP) {
+    return handle_udp_flow(header, send, recv, &options).await;
+  }
 
   // edge server connect
-  let mut local_stream = match TcpStream::connect(edge_server_addr).await {
+  let mut local_stream = match connect_edge_server(&header, &options.policy).await {
     Ok(stream) => stream,
-    Err(e) => {
-      error!("{} | Failed to connect to edge server: {}", id, e);
-      return Err(e.into());
+    Err(frame) => {
+      error!("{} | Failed to connect to edge server: {} ({})", id, frame.status, frame.message);
+      DIAL_FAILURES.with_label_values(&[&header.target(), frame.status.as_str()]).inc();
+      send_status(&mut send, &frame).await?;
+      send.finish()?;
+      return Err(frame.message.into());
     }
   };
   info!("{} |connected to edge server", id);
+  if let Err(message) = write_proxy_protocol(&header, &mut local_stream).await.map_err(|e| e.to_string()) {
+    error!("{} | Failed to write PROXY protocol header: {}", id, message);
+    send_status(&mut send, &StatusFrame::new(ConnectStatus::Error, &message)).await?;
+    send.finish()?;
+    return Err(message.into());
+  }
+  send_status(&mut send, &StatusFrame::new(ConnectStatus::Success, "")).await?;
 
   // stream to stream copy
-  if let Err(e) = stream_to_stream_copy(&mut send, &mut recv, &mut local_stream, &id).await {
+  let limits = CopyLimits { idle_timeout: options.idle_timeout, max_lifetime: options.max_lifetime };
+  let result = stream_to_stream_copy(&mut send, &mut recv, &mut local_stream, &id, limits).await;
+  if let Err(e) = result {
     error!("{} | Stream to stream copy failed: {}", id, e);
     return Err(e);
   }
@@ -36,52 +83,73 @@ pub async fn handle_stream(
   Ok(())
 }
 
-async fn receive_address(
-  recv: &mut quinn::RecvStream,
-  max_vector_size: usize,
-) -> Result<(String, String), Box<dyn Error>> {
-  let mut buf0 = vec![0; max_vector_size];
-  recv.read_exact(&mut buf0).await?;
-  let hellostr: String = String::from_utf8(buf0.to_vec())?.chars().filter(|&c| c != '\0').collect();
-  let helloarray: Vec<&str> = hellostr.split('|').collect();
-  if helloarray.len() != 2 {
-    return Err("Invalid address format".into());
+// Function to resolve the destination of a stream header and check it against the policy
+pub async fn resolve_destination(header: &StreamHeader, policy: &Policy) -> Result<Vec<SocketAddr>, StatusFrame> {
+  let addrs: Vec<_> = match lookup_host((header.host.as_str(), header.port)).await {
+    Ok(addrs) => addrs.collect(),
+    Err(e) => return Err(StatusFrame::new(ConnectStatus::DnsFailure, &e.to_string())),
+  };
+  if addrs.is_empty() {
+    return Err(StatusFrame::new(ConnectStatus::DnsFailure, "No addresses resolved"));
   }
-  Ok((helloarray[0].to_string(), helloarray[1].to_string()))
+  if let Err(reason) = policy.check(&header.host, &addrs, header.port) {
+    warn!("{} | Denied by policy: {}", header.id, reason);
+    return Err(StatusFrame::new(ConnectStatus::DeniedByPolicy, &reason));
+  }
+  Ok(addrs)
 }
 
-async fn stream_to_stream_copy(
+async fn connect_edge_server(header: &StreamHeader, policy: &Policy) -> Result<TcpStream, StatusFrame> {
+  let addrs = resolve_destination(header, policy).await?;
+  match timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS), TcpStream::connect(&addrs[..])).await {
+    Ok(Ok(stream)) => Ok(stream),
+    Ok(Err(e)) => {
+      let status = match e.kind() {
+        io::ErrorKind::ConnectionRefused => ConnectStatus::Refused,
+        io::ErrorKind::TimedOut => ConnectStatus::Timeout,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => ConnectStatus::Unreachable,
+        _ => ConnectStatus::Error,
+      };
+      Err(StatusFrame::new(status, &e.to_string()))
+    }
+    Err(_) => Err(StatusFrame::new(ConnectStatus::Timeout, "Connection timed out")),
+  }
+}
+
+// Function to write a PROXY protocol header to the edge server when sw_listener asked for one
+async fn write_proxy_protocol(header: &StreamHeader, local_stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+  let Some(version) = header.get_metadata(TLV_PROXY_PROTOCOL).and_then(|value| value.first().copied()) else {
+    return Ok(());
+  };
+  let address = |kind| -> Result<SocketAddr, Box<dyn Error>> {
+    let value = header.get_metadata(kind).ok_or("Missing client address in stream header")?;
+    Ok(std::str::from_utf8(value)?.parse()?)
+  };
+  let bytes = proxy_protocol::encode(version, address(TLV_SOURCE_ADDRESS)?, address(TLV_DESTINATION_ADDRESS)?)?;
+  local_stream.write_all(&bytes).await?;
+  Ok(())
+}
+
+pub async fn send_status(send: &mut quinn::SendStream, frame: &StatusFrame) -> Result<(), Box<dyn Error>> {
+  frame.write_to(send).await
+}
+
+// Function to copy both directions between a QUIC stream and the local stream until both are done
+pub async fn stream_to_stream_copy(
   send: &mut quinn::SendStream,
   recv: &mut quinn::RecvStream,
   local_stream: &mut TcpStream,
   id: &str,
+  limits: CopyLimits,
 ) -> Result<(), Box<dyn Error>> {
-  let (mut local_read, mut local_write) = local_stream.split();
-  info!("{} | Stream to stream copy started", id);
-  tokio::select! {
-    recv_result = tokio::io::copy(recv, &mut local_write) => {
-      match recv_result {
-        Ok(bytes_copied) => {
-          info!("{} | Copied {} bytes from recv to manager stream", id, bytes_copied);
-        }
-        Err(e) => {
-          warn!("{} | Failed to copy from recv to manager stream: {}", id, e);
-          return Err(e.into());
-        }
-      }
-    }
-    send_result = tokio::io::copy(&mut local_read, send) => {
-      match send_result {
-        Ok(bytes_copied) => {
-          info!("{} | Copied {} bytes from manager stream to send", id, bytes_copied);
-        }
-        Err(e) => {
-          warn!("{} | Failed to copy from manager stream to send: {}", id, e);
-          return Err(e.into());
-        }
-      }
-    }
+  let to_listener = BYTES_COPIED.with_label_values(&["to_listener"]);
+  let from_listener = BYTES_COPIED.with_label_values(&["from_listener"]);
+  let count = |direction, bytes| match direction {
+    Direction::TcpToQuic => to_listener.inc_by(bytes),
+    Direction::QuicToTcp => from_listener.inc_by(bytes),
   };
-  info!("{} | Stream to stream copy finished", id);
+  if let CopyEnd::TimedOut(reason) = copy::stream_to_stream_copy(send, recv, local_stream, id, limits, count).await? {
+    SESSION_TIMEOUTS.with_label_values(&[reason]).inc();
+  }
   Ok(())
 }
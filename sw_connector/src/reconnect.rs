@@ -0,0 +1,75 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Reconnect settings read from the "reconnect" section of settings.json
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReconnectConfig {
+  pub initial_delay_ms: u64,
+  pub max_delay_ms: u64,
+  pub multiplier: f64,
+  pub jitter: f64,
+  pub max_retries: Option<u32>,
+  pub stable_after_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+  fn default() -> Self {
+    ReconnectConfig {
+      initial_delay_ms: 1000,
+      max_delay_ms: 60000,
+      multiplier: 2.0,
+      jitter: 0.2,
+      max_retries: None,
+      stable_after_ms: 10000,
+    }
+  }
+}
+
+// Exponential backoff with jitter between reconnect attempts
+pub struct Backoff {
+  config: ReconnectConfig,
+  attempt: u32,
+}
+
+impl Backoff {
+  pub fn new(config: ReconnectConfig) -> Self {
+    Backoff { config, attempt: 0 }
+  }
+
+  pub fn attempt(&self) -> u32 {
+    self.attempt
+  }
+
+  pub fn reset(&mut self) {
+    self.attempt = 0;
+  }
+
+  // Function to reset the attempts only when the connection stayed up long enough to count as established
+  //
+  // sw_listener completes the handshake before verifying the connector, so a rejected connection is
+  // dropped right away and must keep counting towards max_retries.
+  pub fn reset_if_stable(&mut self, connected_for: Duration) -> bool {
+    if connected_for < Duration::from_millis(self.config.stable_after_ms) {
+      return false;
+    }
+    self.reset();
+    true
+  }
+
+  // Returns the delay before the next attempt, or None once max_retries is exhausted
+  pub fn next_delay(&mut self) -> Option<Duration> {
+    if let Some(max_retries) = self.config.max_retries {
+      if self.attempt >= max_retries {
+        return None;
+      }
+    }
+    let base = self.config.initial_delay_ms as f64 * self.config.multiplier.max(1.0).powi(self.attempt as i32);
+    let capped = base.min(self.config.max_delay_ms as f64);
+    let jitter = self.config.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 { rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter) } else { 1.0 };
+    self.attempt += 1;
+    Some(Duration::from_millis((capped * factor) as u64))
+  }
+}
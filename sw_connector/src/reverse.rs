@@ -0,0 +1,84 @@
+use crate::metrics::REVERSE_ACCEPTED;
+use crate::quic::stream_to_stream_copy;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use swp_lib::copy::CopyLimits;
+use swp_lib::header::StreamHeader;
+use swp_lib::status::StatusFrame;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+// Pause before accepting again after an error such as EMFILE, so that the loop does not spin
+const ACCEPT_RETRY_MILLIS: u64 = 100;
+
+// Local port read from the "reverse" section of settings.json
+//
+// Connections accepted on listen_address are forwarded through sw_listener to connect_address:connect_port,
+// which has to be reachable from sw_listener and allowed by its SWL_REVERSE_ALLOW setting.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReverseConfig {
+  pub listen_address: String,
+  pub connect_address: String,
+  pub connect_port: u16,
+}
+
+lazy_static! {
+  // QUIC connection to sw_listener, None while reconnecting
+  pub static ref CONNECTION: Arc<RwLock<Option<quinn::Connection>>> = Arc::new(RwLock::new(None));
+}
+
+pub async fn set_connection(connection: Option<quinn::Connection>) {
+  *CONNECTION.write().await = connection;
+}
+
+// Function to bind every reverse port and forward the accepted connections over the current QUIC connection
+pub async fn start_reverse_listeners(configs: &[ReverseConfig]) -> Result<(), Box<dyn Error>> {
+  for config in configs {
+    let listener = TcpListener::bind(&config.listen_address).await?;
+    info!("Reverse port listening on {} for {}:{}", config.listen_address, config.connect_address, config.connect_port);
+    let config = config.clone();
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, peer_address)) => {
+            info!("Accepted reverse connection from: {:?}", peer_address);
+            REVERSE_ACCEPTED.with_label_values(&[&config.listen_address]).inc();
+            let config = config.clone();
+            tokio::spawn(async move {
+              if let Err(e) = handle_reverse_connection(stream, &config).await {
+                error!("Reverse connection from {:?} failed: {}", peer_address, e);
+              }
+            });
+          }
+          Err(e) => {
+            warn!("Failed to accept reverse connection on {}: {}", config.listen_address, e);
+            sleep(Duration::from_millis(ACCEPT_RETRY_MILLIS)).await;
+          }
+        }
+      }
+    });
+  }
+  Ok(())
+}
+
+async fn handle_reverse_connection(mut local_stream: TcpStream, config: &ReverseConfig) -> Result<(), Box<dyn Error>> {
+  let connection = CONNECTION.read().await.clone().ok_or("Not connected to sw_listener")?;
+  let (mut send, mut recv) = connection.open_bi().await?;
+  let id = format!("{}-{}", connection.stable_id(), send.id().index());
+  info!("{} | Opened reverse bi stream", id);
+
+  StreamHeader::new(&id, &config.connect_address, config.connect_port).write_to(&mut send).await?;
+  let frame = StatusFrame::read_from(&mut recv).await?;
+  if !frame.is_success() {
+    warn!("{} | sw_listener failed to connect to {}: {} ({})", id, config.connect_address, frame.status, frame.message);
+    return Ok(());
+  }
+  info!("{} | sw_listener connected to {}:{}", id, config.connect_address, config.connect_port);
+
+  stream_to_stream_copy(&mut send, &mut recv, &mut local_stream, &id, CopyLimits::default()).await
+}
@@ -0,0 +1,101 @@
+use crate::metrics::{BYTES_COPIED, DIAL_FAILURES, SESSION_TIMEOUTS, UDP_FLOWS};
+use crate::quic::{resolve_destination, send_status, StreamOptions};
+use log::{error, info, warn};
+use std::error::Error;
+use std::future;
+use std::net::SocketAddr;
+use swp_lib::datagram::{read_datagram, write_datagram, MAX_DATAGRAM_SIZE};
+use swp_lib::header::{StreamHeader, TLV_IDLE_TIMEOUT, TLV_MAX_LIFETIME};
+use swp_lib::idle::IdleTimer;
+use swp_lib::status::{ConnectStatus, StatusFrame};
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+
+// Function to forward the datagrams of a UDP flow between sw_listener and the edge server
+pub async fn handle_udp_flow(
+  header: StreamHeader,
+  mut send: quinn::SendStream,
+  mut recv: quinn::RecvStream,
+  options: &StreamOptions,
+) -> Result<(), Box<dyn Error>> {
+  let id = header.id.clone();
+  let socket = match bind_edge_socket(&header, options).await {
+    Ok(socket) => socket,
+    Err(frame) => {
+      error!("{} | Failed to open UDP flow to edge server: {} ({})", id, frame.status, frame.message);
+      DIAL_FAILURES.with_label_values(&[&header.target(), frame.status.as_str()]).inc();
+      send_status(&mut send, &frame).await?;
+      send.finish()?;
+      return Err(frame.message.into());
+    }
+  };
+  info!("{} | Opened UDP flow to edge server", id);
+  send_status(&mut send, &StatusFrame::new(ConnectStatus::Success, "")).await?;
+  UDP_FLOWS.inc();
+
+  // Limits set on the opened port of sw_listener take precedence over udp_idle_timeout_secs
+  let idle_timeout = header.get_duration(TLV_IDLE_TIMEOUT).unwrap_or(options.udp_idle_timeout);
+  let max_lifetime = header.get_duration(TLV_MAX_LIFETIME);
+  let idle = IdleTimer::new(idle_timeout);
+  let from_listener = async {
+    loop {
+      let payload = read_datagram(&mut recv).await?;
+      let Some(payload) = payload else {
+        break;
+      };
+      idle.touch();
+      socket.send(&payload).await?;
+      BYTES_COPIED.with_label_values(&["from_listener"]).inc_by(payload.len() as u64);
+    }
+    Ok::<(), Box<dyn Error>>(())
+  };
+  let to_listener = forward_to_listener(&socket, &mut send, &idle);
+  let lifetime_expired = async {
+    match max_lifetime {
+      Some(max_lifetime) => sleep(max_lifetime).await,
+      None => future::pending().await,
+    }
+  };
+  let result = tokio::select! {
+    result = from_listener => result,
+    result = to_listener => result,
+    _ = idle.expired() => {
+      info!("{} | UDP flow expired after {:?} idle", id, idle_timeout);
+      Ok(())
+    }
+    _ = lifetime_expired => {
+      info!("{} | UDP flow reached its max lifetime of {:?}, closing", id, max_lifetime.unwrap_or_default());
+      SESSION_TIMEOUTS.with_label_values(&["lifetime"]).inc();
+      Ok(())
+    }
+  };
+  UDP_FLOWS.dec();
+  let _ = send.finish();
+  if let Err(e) = &result {
+    warn!("{} | UDP flow failed: {}", id, e);
+  }
+  result
+}
+
+async fn forward_to_listener(
+  socket: &UdpSocket,
+  send: &mut quinn::SendStream,
+  idle: &IdleTimer,
+) -> Result<(), Box<dyn Error>> {
+  let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+  loop {
+    let len = socket.recv(&mut buf).await?;
+    idle.touch();
+    write_datagram(send, &buf[..len]).await?;
+    BYTES_COPIED.with_label_values(&["to_listener"]).inc_by(len as u64);
+  }
+}
+
+async fn bind_edge_socket(header: &StreamHeader, options: &StreamOptions) -> Result<UdpSocket, StatusFrame> {
+  let addrs = resolve_destination(header, &options.policy).await?;
+  let addr = addrs[0];
+  let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+  let socket = UdpSocket::bind(local).await.map_err(|e| StatusFrame::new(ConnectStatus::Error, &e.to_string()))?;
+  socket.connect(addr).await.map_err(|e| StatusFrame::new(ConnectStatus::Unreachable, &e.to_string()))?;
+  Ok(socket)
+}
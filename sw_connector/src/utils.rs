@@ -14,7 +14,7 @@ pub fn pem_to_der(pem_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
 
 // Function to load private key from file and convert to DER
 pub fn key_to_der(key_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-  let key_str = String::from_utf8_lossy(&key_data);
+  let key_str = String::from_utf8_lossy(key_data);
   let key_lines: Vec<&str> = key_str.lines().collect();
   let key_base64: String = key_lines.into_iter().filter(|line| !line.starts_with("-----")).collect();
   let der_data = general_purpose::STANDARD.decode(&key_base64)?;
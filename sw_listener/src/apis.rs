@@ -1,127 +1,658 @@
+use crate::auth::{authenticate, on_connect};
+use crate::destination::DestinationList;
+use crate::errors::{ApiError, ErrorCode};
 use crate::hashmap::QUICMAP;
-use crate::quic::handle_stream;
-use actix_web::{delete, get, middleware::Logger, post, web, App, HttpResponse, HttpServer, Responder};
-use log::info;
+use crate::metrics::{self, ACCEPTED_CONNECTIONS, REJECTED_CONNECTIONS};
+use crate::proxy::{handle_proxy_stream, OpenMode};
+use crate::quic::{client_info, handle_stream, ConnectTarget, StreamOptions};
+use crate::session::{self, SessionSet};
+use crate::source::SourceFilter;
+use crate::store;
+use crate::udp::run_udp_listener;
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer, Responder, ResponseError};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use tokio::sync::{RwLock, Semaphore};
 use tokio::task;
 
 type TaskMap = Arc<RwLock<HashMap<u16, TaskInfo>>>;
+// Stored ports that could not be bound at startup, kept in the store and retried until they bind
+type PendingPorts = Arc<Mutex<Vec<OpenObj>>>;
 
-#[derive(Debug, Serialize, Deserialize)]
+const OPENAPI_DOCUMENT: &str = include_str!("openapi.json");
+const DEFAULT_BACKLOG: u32 = 1024;
+const DEFAULT_CLOSE_TIMEOUT_SECS: u64 = 30;
+const RESTORE_RETRY_INTERVAL_SECS: u64 = 10;
+const ACCEPT_RETRY_MILLIS: u64 = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiOptions {
+  pub store_path: Option<String>,
+  pub token: Option<String>,
+  pub tls_config: Option<quinn::rustls::ServerConfig>,
+  pub port_range: Option<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+  #[default]
+  Tcp,
+  Udp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct OpenObj {
   uid: String,
+  #[serde(default)]
   port: u16,
+  #[serde(default = "default_bind_address")]
+  bind_address: IpAddr,
+  #[serde(default)]
   connect_address: String,
+  #[serde(default)]
   connect_port: u16,
+  #[serde(default)]
+  protocol: Protocol,
+  #[serde(default)]
+  mode: OpenMode,
+  #[serde(default)]
+  destinations: Vec<String>,
+  #[serde(default)]
+  proxy_protocol: Option<u8>,
+  #[serde(default)]
+  accept_proxy_protocol: bool,
+  #[serde(default)]
+  allow_sources: Vec<String>,
+  #[serde(default)]
+  deny_sources: Vec<String>,
+  #[serde(default)]
+  max_connections: Option<u32>,
+  #[serde(default)]
+  backlog: Option<u32>,
+  #[serde(default)]
+  idle_timeout_secs: Option<u64>,
+  #[serde(default)]
+  max_lifetime_secs: Option<u64>,
+}
+
+fn default_bind_address() -> IpAddr {
+  IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CloseObj {
   port: u16,
+  #[serde(default)]
+  graceful: bool,
+  #[serde(default)]
+  timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateObj {
+  connect_address: String,
+  connect_port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionQuery {
+  uid: Option<String>,
+  port: Option<u16>,
+  peer_address: Option<IpAddr>,
+  destination: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KickObj {
+  code: Option<u64>,
+  reason: Option<String>,
+  stable_id: Option<usize>,
 }
 
 #[derive(Debug)]
 struct TaskInfo {
   uid: String,
-  connect_address: String,
-  connect_port: u16,
+  bind_address: IpAddr,
+  target: ConnectTarget,
+  protocol: Protocol,
+  mode: OpenMode,
+  destinations: Vec<String>,
+  proxy_protocol: Option<u8>,
+  accept_proxy_protocol: bool,
+  allow_sources: Vec<String>,
+  deny_sources: Vec<String>,
+  max_connections: Option<u32>,
+  backlog: Option<u32>,
+  idle_timeout_secs: Option<u64>,
+  max_lifetime_secs: Option<u64>,
   handle: task::JoinHandle<()>,
+  sessions: SessionSet,
+}
+
+impl TaskInfo {
+  // Function to rebuild the /open request that describes the port as it is now
+  fn spec(&self, port: u16) -> OpenObj {
+    let (connect_address, connect_port) = self.target.get();
+    OpenObj {
+      uid: self.uid.clone(),
+      port,
+      bind_address: self.bind_address,
+      connect_address,
+      connect_port,
+      protocol: self.protocol,
+      mode: self.mode,
+      destinations: self.destinations.clone(),
+      proxy_protocol: self.proxy_protocol,
+      accept_proxy_protocol: self.accept_proxy_protocol,
+      allow_sources: self.allow_sources.clone(),
+      deny_sources: self.deny_sources.clone(),
+      max_connections: self.max_connections,
+      backlog: self.backlog,
+      idle_timeout_secs: self.idle_timeout_secs,
+      max_lifetime_secs: self.max_lifetime_secs,
+    }
+  }
 }
 
 #[post("/open")]
-async fn open(json: web::Json<OpenObj>, task_map: web::Data<TaskMap>) -> impl Responder {
-  let quicmap = QUICMAP.read().await;
-  let max_vector_size: usize = 1024;
+async fn open(
+  json: web::Json<OpenObj>,
+  task_map: web::Data<TaskMap>,
+  pending: web::Data<PendingPorts>,
+  options: web::Data<StreamOptions>,
+  api_options: web::Data<ApiOptions>,
+) -> Result<HttpResponse, ApiError> {
   info!("OpenObj: {:?}", json);
-  let port = json.port.clone();
-  if !quicmap.contains_key(&json.uid) {
-    return HttpResponse::InternalServerError().body("No QUIC connection exists for the specified UID.");
-  }
-  match TcpListener::bind(("0.0.0.0", port)).await {
-    Ok(listener) => {
-      let uid = json.uid.clone();
-      let connect_address = json.connect_address.clone();
-      let connect_port = json.connect_port.clone();
-      let handle = task::spawn({
+  if !QUICMAP.read().await.get(&json.uid).is_some_and(|group| group.is_live()) {
+    let message = format!("No QUIC connection exists for UID {}.", json.uid);
+    return Err(ApiError::new(ErrorCode::UidNotConnected, message));
+  }
+  if json.mode == OpenMode::Forward && json.connect_address.is_empty() {
+    return Err(ApiError::invalid_request("connect_address is required for forward ports."));
+  }
+  if json.mode != OpenMode::Forward && json.protocol != Protocol::Tcp {
+    return Err(ApiError::invalid_request("Proxy modes are only available for TCP ports."));
+  }
+  if json.proxy_protocol.is_some_and(|version| version != 1 && version != 2) {
+    return Err(ApiError::invalid_request("proxy_protocol must be 1 or 2."));
+  }
+  if (json.proxy_protocol.is_some() || json.accept_proxy_protocol) && json.protocol != Protocol::Tcp {
+    return Err(ApiError::invalid_request("PROXY protocol is only available for TCP ports."));
+  }
+  if (json.max_connections.is_some() || json.backlog.is_some()) && json.protocol != Protocol::Tcp {
+    return Err(ApiError::invalid_request("max_connections and backlog are only available for TCP ports."));
+  }
+  if json.max_connections == Some(0) || json.backlog == Some(0) {
+    return Err(ApiError::invalid_request("max_connections and backlog must be greater than 0."));
+  }
+  if json.idle_timeout_secs == Some(0) || json.max_lifetime_secs == Some(0) {
+    return Err(ApiError::invalid_request("idle_timeout_secs and max_lifetime_secs must be greater than 0."));
+  }
+  let mut task_map = task_map.write().await;
+  let pending_ports: Vec<u16> = pending.lock().unwrap().iter().map(|obj| obj.port).collect();
+  let candidates: Vec<u16> = match (json.port, api_options.port_range) {
+    (0, Some((from, to))) => {
+      (from..=to).filter(|port| !task_map.contains_key(port) && !pending_ports.contains(port)).collect()
+    }
+    (0, None) => vec![0],
+    (port, Some((from, to))) if port < from || to < port => {
+      return Err(ApiError::invalid_request(format!("Port {} is outside of the range {}-{}.", port, from, to)));
+    }
+    // Opening the same port again with the same spec is not an error, so that callers can simply retry
+    (port, _) if task_map.contains_key(&port) => {
+      return if task_map[&port].spec(port) == *json {
+        Ok(HttpResponse::Ok().json(json!({ "port": port })))
+      } else {
+        let message = format!("Port {} is already opened with a different spec.", port);
+        Err(ApiError::new(ErrorCode::PortInUse, message))
+      };
+    }
+    (port, _) => vec![port],
+  };
+  if candidates.is_empty() {
+    return Err(ApiError::new(ErrorCode::PortInUse, "No free port left in the range."));
+  }
+  let mut obj = json.into_inner();
+  let mut result = Err(io::Error::from(io::ErrorKind::AddrInUse));
+  for candidate in candidates {
+    obj.port = candidate;
+    result = start_task(&obj, &options).await;
+    match &result {
+      Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+      _ => break,
+    }
+  }
+  match result {
+    Ok((port, task_info)) => {
+      // An explicit /open replaces a stored registration of the port that is still waiting to be restored
+      pending.lock().unwrap().retain(|obj| obj.port != port);
+      task_map.insert(port, task_info);
+      save_task_map(&api_options, &task_map, &pending);
+      Ok(HttpResponse::Ok().json(json!({ "port": port })))
+    }
+    Err(e) => Err(ApiError::from_bind_error(obj.port, &e)),
+  }
+}
+
+// Function to bind the port of obj and spawn its accept loop, returning the bound port
+async fn start_task(obj: &OpenObj, options: &StreamOptions) -> io::Result<(u16, TaskInfo)> {
+  let uid = obj.uid.clone();
+  let target = ConnectTarget::new(obj.connect_address.clone(), obj.connect_port);
+  let mut port = obj.port;
+  let mode = obj.mode;
+  let proxy_protocol = obj.proxy_protocol;
+  let accept_proxy_protocol = obj.accept_proxy_protocol;
+  let destinations = DestinationList::new(&obj.destinations)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+  let destinations = Arc::new(destinations);
+  let sources = SourceFilter::new(&obj.allow_sources, &obj.deny_sources)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+  let sources = Arc::new(sources);
+  let sessions = SessionSet::default();
+  let mut options = options.clone();
+  options.idle_timeout = obj.idle_timeout_secs.map(Duration::from_secs);
+  options.max_lifetime = obj.max_lifetime_secs.map(Duration::from_secs);
+  if let (Protocol::Udp, Some(idle_timeout)) = (obj.protocol, options.idle_timeout) {
+    options.udp_idle_timeout = idle_timeout;
+  }
+  let handle = match obj.protocol {
+    Protocol::Tcp => {
+      let backlog = obj.backlog.unwrap_or(DEFAULT_BACKLOG);
+      let listener = bind_tcp_listener(SocketAddr::new(obj.bind_address, port), backlog)?;
+      let local_address = listener.local_addr()?;
+      port = local_address.port();
+      // Once max_connections sessions are running, new clients wait in the backlog until one ends
+      let permits = obj.max_connections.map_or(Semaphore::MAX_PERMITS, |max| max as usize);
+      let semaphore = Arc::new(Semaphore::new(permits));
+      task::spawn({
         let uid = uid.clone();
-        let connect_address = connect_address.clone();
+        let target = target.clone();
+        let sessions = sessions.clone();
+        let options = options.clone();
         async move {
-          info!("TcpListener created successfully on port {}", json.port);
+          info!("TcpListener created successfully on {}", local_address);
           loop {
+            let Ok(permit) = semaphore.clone().acquire_owned().await else {
+              break;
+            };
             match listener.accept().await {
-              Ok((stream, peer_address)) => {
+              Ok((mut stream, peer_address)) => {
+                if !sources.allows(peer_address.ip()) {
+                  warn!("Rejected connection from {:?} on port {}", peer_address, port);
+                  REJECTED_CONNECTIONS.with_label_values(&[&port.to_string()]).inc();
+                  continue;
+                }
                 info!("Accepted connection from: {:?}", peer_address);
-                let addr = format!("{}:{}", connect_address, connect_port);
-                handle_stream(stream, max_vector_size, &uid, addr).await;
+                ACCEPTED_CONNECTIONS.with_label_values(&[&port.to_string()]).inc();
+                let uid = uid.clone();
+                let target = target.clone();
+                let destinations = destinations.clone();
+                let options = options.clone();
+                sessions.spawn(async move {
+                  let _permit = permit;
+                  let Some(client) =
+                    client_info(&mut stream, peer_address, accept_proxy_protocol, proxy_protocol).await
+                  else {
+                    return;
+                  };
+                  if mode == OpenMode::Forward {
+                    let (connect_address, connect_port) = target.get();
+                    handle_stream(stream, &uid, &connect_address, connect_port, mode, client, &options).await;
+                  } else {
+                    handle_proxy_stream(stream, &uid, mode, &destinations, client, &options).await;
+                  }
+                });
               }
               Err(e) => {
-                info!("Failed to accept connection: {}", e);
-                break;
+                // Accept errors such as EMFILE or ECONNABORTED are transient, the listener is only gone when
+                // the task is aborted by /close
+                warn!("Failed to accept connection on port {}: {}", port, e);
+                tokio::time::sleep(Duration::from_millis(ACCEPT_RETRY_MILLIS)).await;
               }
             }
           }
         }
-      });
-      task_map.write().await.insert(
-        port,
-        TaskInfo {
-          uid,
-          connect_address,
-          connect_port,
-          handle,
-        },
-      );
-      return HttpResponse::Ok().body("TcpListener created successfully!");
+      })
+    }
+    Protocol::Udp => {
+      let socket = UdpSocket::bind((obj.bind_address, port)).await?;
+      let local_address = socket.local_addr()?;
+      port = local_address.port();
+      info!("UdpSocket created successfully on {}", local_address);
+      let options = options.clone();
+      task::spawn(run_udp_listener(socket, uid.clone(), target.clone(), sources, sessions.clone(), options))
     }
+  };
+  let task_info = TaskInfo {
+    uid,
+    bind_address: obj.bind_address,
+    target,
+    protocol: obj.protocol,
+    mode,
+    destinations: obj.destinations.clone(),
+    proxy_protocol,
+    accept_proxy_protocol,
+    allow_sources: obj.allow_sources.clone(),
+    deny_sources: obj.deny_sources.clone(),
+    max_connections: obj.max_connections,
+    backlog: obj.backlog,
+    idle_timeout_secs: obj.idle_timeout_secs,
+    max_lifetime_secs: obj.max_lifetime_secs,
+    handle,
+    sessions,
+  };
+  Ok((port, task_info))
+}
+
+// Function to bind a TCP listener with the given accept backlog
+fn bind_tcp_listener(address: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+  let socket = match address {
+    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+  };
+  socket.set_reuseaddr(true)?;
+  socket.bind(address)?;
+  socket.listen(backlog)
+}
+
+fn save_task_map(api_options: &ApiOptions, task_map: &HashMap<u16, TaskInfo>, pending: &PendingPorts) {
+  let path = match &api_options.store_path {
+    Some(path) => path,
+    None => return,
+  };
+  let mut entries: Vec<OpenObj> = task_map.iter().map(|(&port, task_info)| task_info.spec(port)).collect();
+  entries.extend(pending.lock().unwrap().iter().cloned());
+  if let Err(e) = store::save(path, &entries) {
+    error!("Failed to save opened ports to {}: {}", path, e);
+  }
+}
+
+async fn restore_task_map(
+  api_options: &ApiOptions,
+  task_map: &TaskMap,
+  pending: &PendingPorts,
+  options: &StreamOptions,
+) {
+  let path = match &api_options.store_path {
+    Some(path) => path,
+    None => return,
+  };
+  let entries: Vec<OpenObj> = match store::load(path) {
+    Ok(entries) => entries,
     Err(e) => {
-      let body = format!("Failed to create TcpListener: {}", e);
-      return HttpResponse::InternalServerError().body(body);
+      error!("Failed to load opened ports from {}: {}", path, e);
+      return;
+    }
+  };
+  *pending.lock().unwrap() = entries;
+  if retry_pending_ports(api_options, task_map, pending, options).await > 0 {
+    task::spawn({
+      let (api_options, task_map, pending, options) =
+        (api_options.clone(), task_map.clone(), pending.clone(), options.clone());
+      async move {
+        loop {
+          tokio::time::sleep(Duration::from_secs(RESTORE_RETRY_INTERVAL_SECS)).await;
+          if retry_pending_ports(&api_options, &task_map, &pending, &options).await == 0 {
+            break;
+          }
+        }
+      }
+    });
+  }
+}
+
+// Function to start the stored ports that are not bound yet, returning how many are still pending
+//
+// A port that fails to bind (e.g. still held by another process) stays in the store, so that a
+// temporary failure at startup does not lose the registration.
+async fn retry_pending_ports(
+  api_options: &ApiOptions,
+  task_map: &TaskMap,
+  pending: &PendingPorts,
+  options: &StreamOptions,
+) -> usize {
+  let mut task_map = task_map.write().await;
+  let entries = pending.lock().unwrap().clone();
+  let mut restored = false;
+  for obj in entries {
+    if task_map.contains_key(&obj.port) {
+      warn!("Dropping stored port {} for UID {}, the port has been opened again", obj.port, obj.uid);
+    } else {
+      match start_task(&obj, options).await {
+        Ok((port, task_info)) => {
+          info!("Restored port {} for UID {}", port, obj.uid);
+          task_map.insert(port, task_info);
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+          error!("Dropping stored port {} for UID {}: {}", obj.port, obj.uid, e);
+        }
+        Err(e) => {
+          error!("Failed to restore port {}, retrying in {} s: {}", obj.port, RESTORE_RETRY_INTERVAL_SECS, e);
+          continue;
+        }
+      }
     }
+    pending.lock().unwrap().retain(|entry| entry != &obj);
+    restored = true;
+  }
+  if restored {
+    save_task_map(api_options, &task_map, pending);
   }
+  pending.lock().unwrap().len()
 }
 
 #[delete("/close")]
-async fn close(json: web::Json<CloseObj>, task_map: web::Data<TaskMap>) -> impl Responder {
-  if let Some(task_info) = task_map.write().await.remove(&json.port) {
-    task_info.handle.abort();
-    return HttpResponse::Ok().body(format!("Task {} canceled", &json.port));
-  } else {
-    return HttpResponse::NotFound().body(format!("Task {} not found", &json.port));
+async fn close(
+  json: web::Json<CloseObj>,
+  task_map: web::Data<TaskMap>,
+  pending: web::Data<PendingPorts>,
+  api_options: web::Data<ApiOptions>,
+) -> Result<HttpResponse, ApiError> {
+  let task_info = {
+    let mut task_map = task_map.write().await;
+    let Some(task_info) = task_map.remove(&json.port) else {
+      // A stored port that is still waiting to be restored is only removed from the store
+      let removed = {
+        let mut pending = pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|obj| obj.port != json.port);
+        pending.len() < before
+      };
+      if removed {
+        save_task_map(&api_options, &task_map, &pending);
+        info!("Removed stored port {} that was waiting to be restored", json.port);
+        return Ok(HttpResponse::Ok().json(json!({ "port": json.port, "sessions": 0, "aborted": 0 })));
+      }
+      return Err(ApiError::new(ErrorCode::PortNotFound, format!("Port {} is not opened.", json.port)));
+    };
+    save_task_map(&api_options, &task_map, &pending);
+    task_info
+  };
+  // Stop accepting first, then end the sessions that are still running
+  task_info.handle.abort();
+  let sessions = task_info.sessions.len();
+  if json.graceful && sessions > 0 {
+    let timeout = Duration::from_secs(json.timeout_secs.unwrap_or(DEFAULT_CLOSE_TIMEOUT_SECS));
+    info!("Waiting up to {:?} for {} session(s) on port {} to end", timeout, sessions, json.port);
+    task_info.sessions.wait(timeout).await;
   }
+  let aborted = task_info.sessions.terminate_all().await;
+  info!("Closed port {}: {} session(s), {} aborted", json.port, sessions, aborted);
+  Ok(HttpResponse::Ok().json(json!({ "port": json.port, "sessions": sessions, "aborted": aborted })))
 }
 
 #[get("/list")]
 async fn list(task_map: web::Data<TaskMap>) -> impl Responder {
   let task_map = task_map.read().await;
-  let list: Vec<_> = task_map
+  let list: Vec<OpenObj> = task_map.iter().map(|(&port, task_info)| task_info.spec(port)).collect();
+  HttpResponse::Ok().json(list)
+}
+
+// Function to change where an opened forward port connects to, sessions already established are kept
+#[put("/ports/{port}")]
+async fn update_port(
+  port: web::Path<u16>,
+  json: web::Json<UpdateObj>,
+  task_map: web::Data<TaskMap>,
+  pending: web::Data<PendingPorts>,
+  api_options: web::Data<ApiOptions>,
+) -> Result<HttpResponse, ApiError> {
+  let port = port.into_inner();
+  let task_map = task_map.read().await;
+  let Some(task_info) = task_map.get(&port) else {
+    return Err(ApiError::new(ErrorCode::PortNotFound, format!("Port {} is not opened.", port)));
+  };
+  if task_info.mode != OpenMode::Forward {
+    return Err(ApiError::invalid_request("Only forward ports have a connect address."));
+  }
+  if json.connect_address.is_empty() {
+    return Err(ApiError::invalid_request("connect_address is required for forward ports."));
+  }
+  let UpdateObj { connect_address, connect_port } = json.into_inner();
+  info!("Port {} now connects to {}:{}", port, connect_address, connect_port);
+  task_info.target.set(connect_address, connect_port);
+  save_task_map(&api_options, &task_map, &pending);
+  Ok(HttpResponse::Ok().json(task_info.spec(port)))
+}
+
+#[get("/connections")]
+async fn connections() -> impl Responder {
+  let quicmap = QUICMAP.read().await;
+  let connection_list: Vec<_> = quicmap
     .iter()
-    .map(|(&port, task_info)| {
+    .flat_map(|(uid, group)| group.live().map(move |conn| (uid, conn)))
+    .map(|(uid, conn)| {
       json!({
-        "port": port,
-        "uid": task_info.uid,
-        "connect_address": task_info.connect_address,
-        "connect_port": task_info.connect_port
+        "uid": uid,
+        "remote_address": conn.connection.remote_address().to_string(),
+        "stable_id": conn.connection.stable_id(),
+        "connected_since": conn.connected_at,
+        "rtt_ms": conn.connection.rtt().as_secs_f64() * 1000.0,
+        "open_streams": conn.open_streams()
       })
     })
     .collect();
-  HttpResponse::Ok().json(list)
+  HttpResponse::Ok().json(connection_list)
 }
 
-pub async fn create_app(addr: &str, port: u16) {
-  info!("API listening on {}:{}", addr, port);
+#[delete("/connections/{uid}")]
+async fn kick(uid: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+  let uid = uid.into_inner();
+  // The body is optional, but one that is present must be valid so that a typo never closes every connection
+  let json: KickObj = if body.iter().all(|b| b.is_ascii_whitespace()) {
+    KickObj::default()
+  } else {
+    serde_json::from_slice(&body).map_err(|e| ApiError::invalid_request(format!("Invalid request body: {}", e)))?
+  };
+  let code = json.code.unwrap_or(0);
+  let reason = json.reason.unwrap_or_else(|| "Closed by operator".to_string());
+  // QUIC application error codes are 62-bit varints
+  let code = quinn::VarInt::from_u64(code)
+    .map_err(|_| ApiError::invalid_request(format!("Invalid error code: {} (maximum {})", code, quinn::VarInt::MAX)))?;
+  let stable_id = json.stable_id;
+  let mut quicmap = QUICMAP.write().await;
+  let not_connected =
+    || ApiError::new(ErrorCode::UidNotConnected, format!("No QUIC connection exists for UID {}.", uid));
+  let Some(group) = quicmap.get_mut(&uid) else {
+    return Err(not_connected());
+  };
+  let (closed, kept): (Vec<_>, Vec<_>) = group
+    .connections
+    .drain(..)
+    .partition(|conn| stable_id.is_none_or(|stable_id| conn.connection.stable_id() == stable_id));
+  group.connections = kept;
+  if group.connections.is_empty() {
+    quicmap.remove(&uid);
+  }
+  if closed.is_empty() {
+    return Err(not_connected());
+  }
+  for conn in &closed {
+    conn.connection.close(code, reason.as_bytes());
+    info!("Closed QUIC connection {} for UID {}: {} ({})", conn.connection.stable_id(), uid, code, reason);
+  }
+  Ok(HttpResponse::Ok().json(json!({ "uid": uid, "closed": closed.len() })))
+}
+
+#[get("/sessions")]
+async fn list_sessions(query: web::Query<SessionQuery>) -> impl Responder {
+  let mut sessions: Vec<_> = session::sessions()
+    .into_iter()
+    .filter(|session| query.uid.as_ref().is_none_or(|uid| &session.uid == uid))
+    .filter(|session| query.port.is_none_or(|port| session.port == port))
+    .filter(|session| query.peer_address.is_none_or(|ip| session.peer_address.ip().to_canonical() == ip))
+    .filter(|session| query.destination.as_ref().is_none_or(|destination| &session.destination == destination))
+    .collect();
+  sessions.sort_by_key(|session| session.started_at);
+  let session_list: Vec<_> = sessions.iter().map(|session| session.to_json()).collect();
+  HttpResponse::Ok().json(session_list)
+}
+
+#[delete("/sessions/{id}")]
+async fn terminate_session(id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+  let id = id.into_inner();
+  if !session::terminate(&id) {
+    return Err(ApiError::new(ErrorCode::SessionNotFound, format!("Session {} not found.", id)));
+  }
+  info!("Terminated session {}", id);
+  Ok(HttpResponse::Ok().json(json!({ "id": id })))
+}
+
+#[get("/metrics")]
+async fn metrics_handler(task_map: web::Data<TaskMap>) -> impl Responder {
+  let open_ports = task_map.read().await.len();
+  match metrics::gather(open_ports).await {
+    Ok(body) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body),
+    Err(e) => ApiError::new(ErrorCode::InternalError, format!("Failed to gather metrics: {}", e)).error_response(),
+  }
+}
+
+#[get("/openapi.json")]
+async fn openapi() -> impl Responder {
+  HttpResponse::Ok().content_type("application/json").body(OPENAPI_DOCUMENT)
+}
+
+pub async fn create_app(addr: &str, port: u16, options: StreamOptions, api_options: ApiOptions) {
   let task_map: TaskMap = Arc::new(RwLock::new(HashMap::new()));
+  let pending: PendingPorts = Arc::new(Mutex::new(Vec::new()));
+  restore_task_map(&api_options, &task_map, &pending, &options).await;
+  info!("API listening on {}:{}", addr, port);
+  let tls_config = api_options.tls_config.clone();
   let app = move || {
     App::new()
       .app_data(web::Data::new(task_map.clone()))
+      .app_data(web::Data::new(pending.clone()))
+      .app_data(web::Data::new(options.clone()))
+      .app_data(web::Data::new(api_options.clone()))
+      .app_data(web::JsonConfig::default().error_handler(|e, _| ApiError::invalid_request(e.to_string()).into()))
+      .app_data(web::QueryConfig::default().error_handler(|e, _| ApiError::invalid_request(e.to_string()).into()))
+      .app_data(web::PathConfig::default().error_handler(|e, _| ApiError::invalid_request(e.to_string()).into()))
+      .wrap(from_fn(authenticate))
       .wrap(Logger::default())
       .service(open)
       .service(close)
       .service(list)
+      .service(update_port)
+      .service(connections)
+      .service(kick)
+      .service(list_sessions)
+      .service(terminate_session)
+      .service(metrics_handler)
+      .service(openapi)
+  };
+  let server = HttpServer::new(app).on_connect(on_connect);
+  let server = match tls_config {
+    Some(tls_config) => server.bind_rustls_0_23((addr, port), tls_config),
+    None => server.bind((addr, port)),
   };
-  HttpServer::new(app).bind((addr, port)).expect("Cannot bind to address").run().await.expect("Server failed");
+  server.expect("Cannot bind to address").run().await.expect("Server failed");
 }
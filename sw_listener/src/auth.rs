@@ -0,0 +1,80 @@
+use crate::apis::ApiOptions;
+use crate::errors::{ApiError, ErrorCode};
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use log::{info, warn};
+use std::any::Any;
+use tokio::net::TcpStream;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// Subject of the client certificate presented on the API connection
+#[derive(Debug, Clone)]
+pub struct ClientIdentity(pub String);
+
+// Function to record the client certificate subject of a mTLS connection for audit logging
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+  let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+    return;
+  };
+  let subject = tls
+    .get_ref()
+    .1
+    .peer_certificates()
+    .and_then(|certs| certs.first())
+    .and_then(|cert| X509Certificate::from_der(cert.as_ref()).ok().map(|(_, cert)| cert.subject().to_string()));
+  if let Some(subject) = subject {
+    ext.insert(ClientIdentity(subject));
+  }
+}
+
+// Middleware that checks the bearer token and writes an audit log line for every API call
+pub async fn authenticate(
+  req: ServiceRequest,
+  next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+  let peer = req.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "-".to_string());
+  let identity = req.conn_data::<ClientIdentity>().map(|id| id.0.clone()).unwrap_or_else(|| "-".to_string());
+  let caller = format!("{} ({})", peer, identity);
+  let method = req.method().clone();
+  let path = req.path().to_string();
+
+  let expected = req.app_data::<web::Data<ApiOptions>>().and_then(|options| options.token.clone());
+  if let Some(expected) = expected {
+    let provided = req
+      .headers()
+      .get(header::AUTHORIZATION)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .map(|value| value.trim().to_string());
+    let rejection = match provided {
+      None => {
+        let mut response = ApiError::new(ErrorCode::Unauthorized, "Missing bearer token.").error_response();
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        Some(response)
+      }
+      Some(token) if !constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+        Some(ApiError::new(ErrorCode::Forbidden, "Invalid bearer token.").error_response())
+      }
+      Some(_) => None,
+    };
+    if let Some(response) = rejection {
+      warn!("API {} {} from {} rejected: {}", method, path, caller, response.status());
+      return Ok(req.into_response(response).map_into_right_body());
+    }
+  }
+
+  let res = next.call(req).await?;
+  info!("API {} {} from {}: {}", method, path, caller, res.status());
+  Ok(res.map_into_left_body())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
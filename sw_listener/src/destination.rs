@@ -0,0 +1,84 @@
+use std::error::Error;
+use swp_lib::pattern::{parse_port_range, HostPattern};
+
+// Destinations a proxy port may forward to, given as "host:port" entries
+//
+// host accepts CIDRs, IP addresses and host names with "*" wildcards (IPv6 in brackets),
+// port accepts a number, a "from-to" range or "*". An empty list allows every destination.
+#[derive(Debug, Default)]
+pub struct DestinationList {
+  rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+  host: HostPattern,
+  ports: (u16, u16),
+}
+
+impl DestinationList {
+  pub fn new(entries: &[String]) -> Result<Self, Box<dyn Error>> {
+    Ok(DestinationList { rules: entries.iter().map(|entry| Rule::new(entry)).collect::<Result<_, _>>()? })
+  }
+
+  pub fn allows(&self, host: &str, port: u16) -> bool {
+    self.rules.is_empty() || self.rules.iter().any(|rule| rule.matches(host, port))
+  }
+}
+
+impl Rule {
+  fn new(entry: &str) -> Result<Self, Box<dyn Error>> {
+    let (host, port) = entry.rsplit_once(':').ok_or_else(|| format!("Invalid destination: {}", entry))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = HostPattern::parse(host).map_err(|e| format!("{} in destination {}", e, entry))?;
+    let ports = match port {
+      "*" => (0, u16::MAX),
+      _ => parse_port_range(port).map_err(|e| format!("{} in destination {}", e, entry))?,
+    };
+    Ok(Rule { host, ports })
+  }
+
+  fn matches(&self, host: &str, port: u16) -> bool {
+    self.ports.0 <= port && port <= self.ports.1 && self.host.matches(host)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn destinations(entries: &[&str]) -> DestinationList {
+    DestinationList::new(&entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>()).unwrap()
+  }
+
+  #[test]
+  fn empty_list_allows_everything() {
+    assert!(destinations(&[]).allows("example.com", 22));
+  }
+
+  #[test]
+  fn bracketed_ipv6_and_any_port() {
+    let list = destinations(&["[2001:db8::/32]:*", "[::1]:8080"]);
+    assert!(list.allows("2001:db8::1", 1));
+    assert!(list.allows("2001:db8:ffff::1", 65535));
+    assert!(list.allows("::1", 8080));
+    assert!(!list.allows("::1", 8081));
+    assert!(!list.allows("2001:db9::1", 443));
+  }
+
+  #[test]
+  fn host_names_and_port_ranges() {
+    let list = destinations(&["*.example.com:443", "10.0.0.0/8:8000-8999"]);
+    assert!(list.allows("www.example.com", 443));
+    assert!(!list.allows("www.example.com", 80));
+    assert!(list.allows("10.1.2.3", 8080));
+    assert!(!list.allows("10.1.2.3", 9000));
+  }
+
+  #[test]
+  fn rejects_invalid_entries() {
+    for entry in ["10.0.0.0/33:443", "[2001:db8::/129]:*", "example.com", "example.com:99999", "example.com:90-80"] {
+      assert!(DestinationList::new(&[entry.to_string()]).is_err(), "{}", entry);
+    }
+  }
+}
@@ -0,0 +1,79 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+use std::io;
+
+// Error codes returned by the management API, clients match on these instead of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  UidNotConnected,
+  PortInUse,
+  PortNotFound,
+  SessionNotFound,
+  InvalidRequest,
+  BindFailed,
+  Unauthorized,
+  Forbidden,
+  InternalError,
+}
+
+impl ErrorCode {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      ErrorCode::UidNotConnected | ErrorCode::PortNotFound | ErrorCode::SessionNotFound => StatusCode::NOT_FOUND,
+      ErrorCode::PortInUse => StatusCode::CONFLICT,
+      ErrorCode::InvalidRequest => StatusCode::UNPROCESSABLE_ENTITY,
+      ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+      ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+      ErrorCode::BindFailed | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
+
+//
+// Body of every error response
+//
+// { "error": { "code": "port_not_found", "message": "Port 8080 not found" } }
+//
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+  pub code: ErrorCode,
+  pub message: String,
+}
+
+impl ApiError {
+  pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+    ApiError { code, message: message.into() }
+  }
+
+  pub fn invalid_request(message: impl Into<String>) -> Self {
+    ApiError::new(ErrorCode::InvalidRequest, message)
+  }
+
+  // Function to classify an error from binding an opened port
+  pub fn from_bind_error(port: u16, e: &io::Error) -> Self {
+    match e.kind() {
+      io::ErrorKind::AddrInUse => ApiError::new(ErrorCode::PortInUse, format!("Port {} is already in use", port)),
+      io::ErrorKind::InvalidInput => ApiError::invalid_request(e.to_string()),
+      _ => ApiError::new(ErrorCode::BindFailed, format!("Failed to bind port {}: {}", port, e)),
+    }
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}: {}", self.code, self.message)
+  }
+}
+
+impl ResponseError for ApiError {
+  fn status_code(&self) -> StatusCode {
+    self.code.status()
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    HttpResponse::build(self.status_code()).json(serde_json::json!({ "error": self }))
+  }
+}
@@ -1,8 +1,107 @@
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 
+#[derive(Debug, Clone)]
+pub struct QuicConnection {
+  pub connection: quinn::Connection,
+  pub connected_at: u64,
+  open_streams: Arc<AtomicUsize>,
+}
+
+impl QuicConnection {
+  pub fn new(connection: quinn::Connection) -> Self {
+    let connected_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    QuicConnection { connection, connected_at, open_streams: Arc::new(AtomicUsize::new(0)) }
+  }
+
+  pub fn open_streams(&self) -> usize {
+    self.open_streams.load(Ordering::Relaxed)
+  }
+
+  // Counts a stream as open until the returned guard is dropped
+  pub fn stream_guard(&self) -> StreamGuard {
+    self.open_streams.fetch_add(1, Ordering::Relaxed);
+    StreamGuard { open_streams: self.open_streams.clone() }
+  }
+}
+
+// How new streams are spread over the connections of a UID
+#[derive(Debug, Clone, Copy, Default)]
+pub enum BalancePolicy {
+  #[default]
+  RoundRobin,
+  LeastStreams,
+}
+
+impl BalancePolicy {
+  pub fn parse(policy: &str) -> Result<Self, Box<dyn Error>> {
+    match policy {
+      "round_robin" => Ok(BalancePolicy::RoundRobin),
+      "least_streams" => Ok(BalancePolicy::LeastStreams),
+      _ => Err(format!("Invalid balance policy: {}", policy).into()),
+    }
+  }
+}
+
+// Every connection verified for a UID
+#[derive(Debug, Default)]
+pub struct ConnectorGroup {
+  pub connections: Vec<QuicConnection>,
+  next: AtomicUsize,
+}
+
+impl ConnectorGroup {
+  pub fn live(&self) -> impl Iterator<Item = &QuicConnection> {
+    self.connections.iter().filter(|conn| conn.connection.close_reason().is_none())
+  }
+
+  pub fn is_live(&self) -> bool {
+    self.live().next().is_some()
+  }
+
+  // Returns the live connections in the order they should be tried for a new stream
+  pub fn candidates(&self, balance: BalancePolicy) -> Vec<QuicConnection> {
+    let mut live: Vec<QuicConnection> = self.live().cloned().collect();
+    if live.is_empty() {
+      return live;
+    }
+    match balance {
+      BalancePolicy::RoundRobin => {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % live.len();
+        live.rotate_left(start);
+      }
+      BalancePolicy::LeastStreams => live.sort_by_key(|conn| conn.open_streams()),
+    }
+    live
+  }
+}
+
+pub struct StreamGuard {
+  open_streams: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamGuard {
+  fn drop(&mut self) {
+    self.open_streams.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
 lazy_static! {
-  pub static ref QUICMAP: Arc<RwLock<HashMap<String, quinn::Connection>>> = Arc::new(RwLock::new(HashMap::new()));
+  pub static ref QUICMAP: Arc<RwLock<HashMap<String, ConnectorGroup>>> = Arc::new(RwLock::new(HashMap::new()));
+}
+
+// Function to drop a connection from QUICMAP once it has been closed
+pub async fn remove_connection(uid: &str, stable_id: usize) {
+  let mut map = QUICMAP.write().await;
+  if let Some(group) = map.get_mut(uid) {
+    group.connections.retain(|conn| conn.connection.stable_id() != stable_id);
+    if group.connections.is_empty() {
+      map.remove(uid);
+    }
+  }
 }
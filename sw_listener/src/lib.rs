@@ -1,4 +1,14 @@
 pub mod apis;
+pub mod auth;
+pub mod destination;
+pub mod errors;
 pub mod hashmap;
+pub mod metrics;
+pub mod proxy;
 pub mod quic;
+pub mod reverse;
+pub mod session;
+pub mod source;
+pub mod store;
+pub mod udp;
 pub mod utils;
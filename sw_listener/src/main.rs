@@ -5,13 +5,16 @@ use std::env;
 use std::net::ToSocketAddrs;
 use std::time::Duration;
 use std::{error::Error, io, sync::Arc};
-use swl_lib::apis::create_app;
-use swl_lib::quic::handle_quic_connection;
-use swl_lib::utils::get_env;
+use swl_lib::apis::{create_app, ApiOptions};
+use swl_lib::hashmap::BalancePolicy;
+use swl_lib::quic::{handle_quic_connection, StreamOptions, TakeoverPolicy};
+use swl_lib::utils::{get_env, read_file};
 use tokio::signal;
 
 const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
 const MAX_CONCURRENT_UNI_STREAMS: u8 = 0;
+// Bi streams sw_connector may open at once for its reverse ports
+const MAX_CONCURRENT_BIDI_STREAMS: u32 = 100;
 const KEEP_ALIVE_INTERVAL_SECS: u64 = 50;
 const MAX_IDLE_TIMEOUT_SECS: u64 = 60;
 
@@ -23,9 +26,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
   let swl_addrs = get_env("SWL_ADDRS", "0.0.0.0");
   let swl_port: u16 = get_env("SWL_PORT", "11443").parse()?;
   let swl_scep_url = get_env("SWL_SCEP_URL", "http://127.0.0.1:3000/api/cert/verify");
+  let swl_takeover_policy = get_env("SWL_TAKEOVER_POLICY", "reject");
+  let swl_takeover_probe_ms: u64 = get_env("SWL_TAKEOVER_PROBE_MS", "3000").parse()?;
 
   let apis_addrs = get_env("APIS_ADDRS", "0.0.0.0");
   let apis_port: u16 = get_env("APIS_PORT", "8081").parse()?;
+  let swl_balance_policy = get_env("SWL_BALANCE_POLICY", "round_robin");
+  let swl_udp_idle_timeout_secs: u64 = get_env("SWL_UDP_IDLE_TIMEOUT_SECS", "60").parse()?;
+  let swl_reverse_allow = get_env("SWL_REVERSE_ALLOW", "");
+  let swl_rst_on_connect_failure: bool = get_env("SWL_RST_ON_CONNECT_FAILURE", "false").parse()?;
+  let swl_store_path = get_env("SWL_STORE_PATH", "");
+  let swl_port_range = get_env("SWL_PORT_RANGE", "");
+  let apis_token = get_env("APIS_TOKEN", "");
+  let apis_token_file = get_env("APIS_TOKEN_FILE", "");
+  let apis_cert_path = get_env("APIS_CERT_PATH", "");
+  let apis_key_path = get_env("APIS_KEY_PATH", "");
+  let apis_ca_path = get_env("APIS_CA_PATH", "");
 
   let swl_log_level = get_env("SWL_LOG_LEVEL", "info").to_string();
   env::set_var("RUST_LOG", &swl_log_level);
@@ -38,8 +54,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
   debug!("SWL_ADDRS: {}", swl_addrs);
   debug!("SWL_PORT: {}", swl_port);
   debug!("SWL_SCEP_URL: {}", swl_scep_url);
+  debug!("SWL_TAKEOVER_POLICY: {}", swl_takeover_policy);
+  debug!("SWL_TAKEOVER_PROBE_MS: {}", swl_takeover_probe_ms);
   debug!("APIS_ADDRS: {}", apis_addrs);
   debug!("APIS_PORT: {}", apis_port);
+  debug!("SWL_BALANCE_POLICY: {}", swl_balance_policy);
+  debug!("SWL_UDP_IDLE_TIMEOUT_SECS: {}", swl_udp_idle_timeout_secs);
+  debug!("SWL_REVERSE_ALLOW: {}", swl_reverse_allow);
+  debug!("SWL_RST_ON_CONNECT_FAILURE: {}", swl_rst_on_connect_failure);
+  debug!("SWL_STORE_PATH: {}", swl_store_path);
+  debug!("SWL_PORT_RANGE: {}", swl_port_range);
+  debug!("APIS_TOKEN_FILE: {}", apis_token_file);
+  debug!("APIS_CERT_PATH: {}", apis_cert_path);
+  debug!("APIS_KEY_PATH: {}", apis_key_path);
+  debug!("APIS_CA_PATH: {}", apis_ca_path);
 
   let (certs, key) = load_certificates(&swl_cert_path, &swl_key_path)?;
   debug!("Loaded certificates and key");
@@ -47,25 +75,58 @@ async fn main() -> Result<(), Box<dyn Error>> {
   let server_auth_roots = load_ca_certificate(&swl_ca_path)?;
   debug!("Loaded CA certificate");
 
+  let takeover = TakeoverPolicy::parse(&swl_takeover_policy, swl_takeover_probe_ms)?;
+  let balance = BalancePolicy::parse(&swl_balance_policy)?;
+
   let server_config = create_server_config(certs, key, server_auth_roots)?;
   debug!("Created server config");
 
   let server_addrs = (swl_addrs.clone(), swl_port).to_socket_addrs()?.next().ok_or_else(|| {
-    io::Error::new(
-      io::ErrorKind::Other,
-      format!("Failed to resolve address: {}:{}", swl_addrs, swl_port),
-    )
+    io::Error::other(format!("Failed to resolve address: {}:{}", swl_addrs, swl_port))
   })?;
   let endpoint = quinn::Endpoint::server(server_config, server_addrs)?;
   info!("QUIC listening on {}", endpoint.local_addr()?);
 
-  let apis_task = tokio::spawn(async move { create_app(&apis_addrs, apis_port).await });
+  let stream_options = StreamOptions {
+    rst_on_connect_failure: swl_rst_on_connect_failure,
+    balance,
+    udp_idle_timeout: Duration::from_secs(swl_udp_idle_timeout_secs),
+    reverse_allow: swl_reverse_allow.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+    // Session limits are set per opened port
+    idle_timeout: None,
+    max_lifetime: None,
+  };
+  let apis_token = if !apis_token_file.is_empty() {
+    let token = String::from_utf8(read_file(&apis_token_file, "Failed to read API token file")?)?.trim().to_string();
+    if token.is_empty() {
+      return Err(format!("API token file is empty: {}", apis_token_file).into());
+    }
+    token
+  } else {
+    apis_token
+  };
+  let apis_tls_config = if !apis_cert_path.is_empty() {
+    let (certs, key) = load_certificates(&apis_cert_path, &apis_key_path)?;
+    let client_auth_roots = load_ca_certificate(&apis_ca_path)?;
+    debug!("Loaded API certificates and CA certificate");
+    Some(create_api_tls_config(certs, key, client_auth_roots)?)
+  } else {
+    None
+  };
+  let api_options = ApiOptions {
+    store_path: Some(swl_store_path).filter(|path| !path.is_empty()),
+    token: Some(apis_token).filter(|token| !token.is_empty()),
+    tls_config: apis_tls_config,
+    port_range: parse_port_range(&swl_port_range)?,
+  };
+  let quic_stream_options = stream_options.clone();
+  let apis_task = tokio::spawn(async move { create_app(&apis_addrs, apis_port, stream_options, api_options).await });
   let quic_task = tokio::spawn(async move {
     while let Some(conn) = endpoint.accept().await {
-      let fut = handle_quic_connection(conn, swl_scep_url.clone());
+      let fut = handle_quic_connection(conn, swl_scep_url.clone(), takeover, quic_stream_options.clone());
       tokio::spawn(async move {
         if let Err(e) = fut.await {
-          error!("connection failed: {reason}", reason = e.to_string())
+          error!("connection failed: {reason}", reason = e)
         }
       });
     }
@@ -97,6 +158,18 @@ fn load_certificates(
   Ok((vec![cert], key))
 }
 
+fn parse_port_range(range: &str) -> Result<Option<(u16, u16)>, Box<dyn Error>> {
+  if range.is_empty() {
+    return Ok(None);
+  }
+  let (from, to) = range.split_once('-').ok_or_else(|| format!("Invalid port range: {}", range))?;
+  let (from, to) = (from.trim().parse::<u16>()?, to.trim().parse::<u16>()?);
+  if from == 0 || from > to {
+    return Err(format!("Invalid port range: {}", range).into());
+  }
+  Ok(Some((from, to)))
+}
+
 fn load_ca_certificate(ca_path: &str) -> Result<quinn::rustls::RootCertStore, Box<dyn Error>> {
   let mut server_auth_roots = quinn::rustls::RootCertStore::empty();
   let root: CertificateDer<'static> = CertificateDer::from_pem_file(ca_path)?;
@@ -118,7 +191,19 @@ fn create_server_config(
   Arc::get_mut(&mut server_config.transport)
     .unwrap()
     .max_concurrent_uni_streams(MAX_CONCURRENT_UNI_STREAMS.into())
+    .max_concurrent_bidi_streams(MAX_CONCURRENT_BIDI_STREAMS.into())
     .keep_alive_interval(Some(Duration::from_secs(KEEP_ALIVE_INTERVAL_SECS)))
     .max_idle_timeout(Some(Duration::from_secs(MAX_IDLE_TIMEOUT_SECS).try_into()?));
   Ok(server_config)
 }
+
+fn create_api_tls_config(
+  certs: Vec<CertificateDer<'static>>,
+  key: PrivateKeyDer<'static>,
+  roots: quinn::rustls::RootCertStore,
+) -> Result<quinn::rustls::ServerConfig, Box<dyn Error>> {
+  let cert_verifier = quinn::rustls::server::WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+  let server_crypto =
+    quinn::rustls::ServerConfig::builder().with_client_cert_verifier(cert_verifier).with_single_cert(certs, key)?;
+  Ok(server_crypto)
+}
@@ -0,0 +1,94 @@
+use crate::hashmap::QUICMAP;
+use lazy_static::lazy_static;
+use prometheus::{
+  register_gauge_vec, register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+  register_int_gauge_vec, Encoder, GaugeVec, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+  pub static ref CONNECTED_UIDS: IntGauge =
+    register_int_gauge!("swl_connected_uids", "Number of UIDs with a live QUIC connection").unwrap();
+  pub static ref OPEN_PORTS: IntGauge = register_int_gauge!("swl_open_ports", "Number of opened ports").unwrap();
+  pub static ref ACCEPTED_CONNECTIONS: IntCounterVec =
+    register_int_counter_vec!("swl_accepted_connections_total", "TCP connections accepted per port", &["port"])
+      .unwrap();
+  pub static ref REJECTED_CONNECTIONS: IntCounterVec = register_int_counter_vec!(
+    "swl_rejected_connections_total",
+    "Connections rejected by the source filter per port",
+    &["port"]
+  )
+  .unwrap();
+  pub static ref UDP_FLOWS: IntGauge =
+    register_int_gauge!("swl_udp_flows", "Number of active UDP flows on opened ports").unwrap();
+  pub static ref BYTES_COPIED: IntCounterVec = register_int_counter_vec!(
+    "swl_bytes_copied_total",
+    "Bytes copied between TCP clients and sw_connector",
+    &["direction"]
+  )
+  .unwrap();
+  pub static ref SESSION_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+    "swl_session_timeouts_total",
+    "Sessions closed by the idle timeout or the max lifetime of their port",
+    &["reason"]
+  )
+  .unwrap();
+  pub static ref STREAM_OPEN_FAILURES: IntCounter =
+    register_int_counter!("swl_stream_open_failures_total", "Failures to open a QUIC stream to sw_connector").unwrap();
+  pub static ref CONNECT_RESULTS: IntCounterVec = register_int_counter_vec!(
+    "swl_connect_results_total",
+    "Connect results reported by sw_connector",
+    &["status"]
+  )
+  .unwrap();
+  pub static ref REVERSE_STREAMS: IntCounterVec = register_int_counter_vec!(
+    "swl_reverse_streams_total",
+    "Reverse streams opened by sw_connector per connect result",
+    &["status"]
+  )
+  .unwrap();
+  pub static ref SCEP_VERIFICATIONS: IntCounterVec = register_int_counter_vec!(
+    "swl_scep_verifications_total",
+    "Client certificate verifications against the SCEP server",
+    &["outcome"]
+  )
+  .unwrap();
+  pub static ref SCEP_VERIFICATION_SECONDS: Histogram =
+    register_histogram!("swl_scep_verification_seconds", "Latency of client certificate verification").unwrap();
+  pub static ref QUIC_RTT_SECONDS: GaugeVec =
+    register_gauge_vec!("swl_quic_rtt_seconds", "Round trip time of the QUIC connection", &["uid", "stable_id"])
+      .unwrap();
+  pub static ref QUIC_LOST_PACKETS: IntGaugeVec =
+    register_int_gauge_vec!("swl_quic_lost_packets", "Packets lost on the QUIC connection", &["uid", "stable_id"])
+      .unwrap();
+  pub static ref QUIC_SENT_PACKETS: IntGaugeVec =
+    register_int_gauge_vec!("swl_quic_sent_packets", "Packets sent on the QUIC connection", &["uid", "stable_id"])
+      .unwrap();
+}
+
+// Function to refresh the gauges taken from live state and render every metric in the text format
+pub async fn gather(open_ports: usize) -> Result<String, Box<dyn std::error::Error>> {
+  OPEN_PORTS.set(open_ports as i64);
+  QUIC_RTT_SECONDS.reset();
+  QUIC_LOST_PACKETS.reset();
+  QUIC_SENT_PACKETS.reset();
+  let map = QUICMAP.read().await;
+  let mut connected = 0;
+  for (uid, group) in map.iter() {
+    let mut live = false;
+    for quic_connection in group.live() {
+      let stats = quic_connection.connection.stats();
+      let stable_id = quic_connection.connection.stable_id().to_string();
+      let labels = [uid.as_str(), stable_id.as_str()];
+      QUIC_RTT_SECONDS.with_label_values(&labels).set(stats.path.rtt.as_secs_f64());
+      QUIC_LOST_PACKETS.with_label_values(&labels).set(stats.path.lost_packets as i64);
+      QUIC_SENT_PACKETS.with_label_values(&labels).set(stats.path.sent_packets as i64);
+      live = true;
+    }
+    connected += live as i64;
+  }
+  CONNECTED_UIDS.set(connected);
+
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+  Ok(String::from_utf8(buffer)?)
+}
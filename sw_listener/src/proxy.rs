@@ -0,0 +1,270 @@
+use crate::destination::DestinationList;
+use crate::quic::{handle_stream, ClientInfo, StreamOptions};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use swp_lib::status::{ConnectStatus, StatusFrame};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const MAX_HTTP_HEADER_SIZE: usize = 8192;
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+// Destination requested by the client and any data it sent after the request
+type Handshake = (String, u16, Vec<u8>);
+
+// How an opened port picks the destination of a connection
+//
+// forward sends every connection to the connect_address of the port, socks5 and http_connect
+// let the client ask for the destination before any data is forwarded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenMode {
+  #[default]
+  Forward,
+  Socks5,
+  HttpConnect,
+}
+
+impl OpenMode {
+  // Function to read the destination requested by the client
+  pub async fn handshake(&self, stream: &mut TcpStream) -> Result<Handshake, Box<dyn Error>> {
+    match self {
+      OpenMode::Forward => Err("Forward ports have no handshake".into()),
+      OpenMode::Socks5 => socks5_handshake(stream).await,
+      OpenMode::HttpConnect => http_connect_handshake(stream).await,
+    }
+  }
+
+  // Function to tell the client whether sw_connector reached the destination
+  pub async fn reply(&self, stream: &mut TcpStream, frame: &StatusFrame) -> Result<(), Box<dyn Error>> {
+    match self {
+      OpenMode::Forward => Ok(()),
+      OpenMode::Socks5 => {
+        let code: u8 = match frame.status {
+          ConnectStatus::Success => 0x00,
+          ConnectStatus::DeniedByPolicy => 0x02,
+          ConnectStatus::Unreachable => 0x03,
+          ConnectStatus::DnsFailure => 0x04,
+          ConnectStatus::Refused => 0x05,
+          ConnectStatus::Timeout => 0x06,
+          ConnectStatus::Error => 0x01,
+        };
+        stream.write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+        Ok(())
+      }
+      OpenMode::HttpConnect => {
+        let status = match frame.status {
+          ConnectStatus::Success => "200 Connection Established",
+          ConnectStatus::DeniedByPolicy => "403 Forbidden",
+          ConnectStatus::Timeout => "504 Gateway Timeout",
+          _ => "502 Bad Gateway",
+        };
+        stream.write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes()).await?;
+        Ok(())
+      }
+    }
+  }
+}
+
+// Function to serve a connection accepted on a socks5 or http_connect port
+pub async fn handle_proxy_stream(
+  mut stream: TcpStream,
+  uid: &str,
+  mode: OpenMode,
+  destinations: &DestinationList,
+  mut client: ClientInfo,
+  options: &StreamOptions,
+) {
+  // A client that connects and never sends its request must not hold the task and its connection permit
+  let (host, port) = match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), mode.handshake(&mut stream)).await {
+    Ok(Ok((host, port, early_data))) => {
+      client.early_data = early_data;
+      (host, port)
+    }
+    Ok(Err(e)) => {
+      warn!("Failed {:?} handshake: {}", mode, e);
+      return;
+    }
+    Err(_) => {
+      warn!("Timed out waiting for {:?} handshake from {:?}", mode, client.address);
+      return;
+    }
+  };
+  if !destinations.allows(&host, port) {
+    warn!("Destination {}:{} is not allowed on this port", host, port);
+    let frame = StatusFrame::new(ConnectStatus::DeniedByPolicy, "Destination not allowed");
+    let _ = mode.reply(&mut stream, &frame).await;
+    return;
+  }
+  info!("Requested destination: {}:{}", host, port);
+  handle_stream(stream, uid, &host, port, mode, client, options).await;
+}
+
+// Only the no authentication method and the CONNECT command are supported
+async fn socks5_handshake(stream: &mut TcpStream) -> Result<Handshake, Box<dyn Error>> {
+  let mut greeting = [0u8; 2];
+  stream.read_exact(&mut greeting).await?;
+  if greeting[0] != 0x05 {
+    return Err(format!("Unsupported SOCKS version: {}", greeting[0]).into());
+  }
+  let mut methods = vec![0u8; greeting[1] as usize];
+  stream.read_exact(&mut methods).await?;
+  if !methods.contains(&0x00) {
+    stream.write_all(&[0x05, 0xff]).await?;
+    return Err("No acceptable SOCKS authentication method".into());
+  }
+  stream.write_all(&[0x05, 0x00]).await?;
+
+  let mut request = [0u8; 4];
+  stream.read_exact(&mut request).await?;
+  if request[1] != 0x01 {
+    stream.write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    return Err(format!("Unsupported SOCKS command: {}", request[1]).into());
+  }
+  let host = match request[3] {
+    0x01 => {
+      let mut addr = [0u8; 4];
+      stream.read_exact(&mut addr).await?;
+      Ipv4Addr::from(addr).to_string()
+    }
+    0x03 => {
+      let length = stream.read_u8().await?;
+      let mut name = vec![0u8; length as usize];
+      stream.read_exact(&mut name).await?;
+      String::from_utf8(name)?
+    }
+    0x04 => {
+      let mut addr = [0u8; 16];
+      stream.read_exact(&mut addr).await?;
+      Ipv6Addr::from(addr).to_string()
+    }
+    atyp => {
+      stream.write_all(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+      return Err(format!("Unsupported SOCKS address type: {}", atyp).into());
+    }
+  };
+  let port = stream.read_u16().await?;
+  Ok((host, port, Vec::new()))
+}
+
+async fn http_connect_handshake(stream: &mut TcpStream) -> Result<Handshake, Box<dyn Error>> {
+  let mut reader = BufReader::new(&mut *stream);
+  let mut header = Vec::new();
+  while !header.ends_with(b"\r\n\r\n") {
+    if header.len() >= MAX_HTTP_HEADER_SIZE {
+      return Err("HTTP request header too large".into());
+    }
+    let limit = (MAX_HTTP_HEADER_SIZE - header.len()) as u64;
+    if (&mut reader).take(limit).read_until(b'\n', &mut header).await? == 0 {
+      return Err("Connection closed before the end of the HTTP request header".into());
+    }
+  }
+  // Whatever was read past the header belongs to the tunnelled stream
+  let early_data = reader.buffer().to_vec();
+  let header = String::from_utf8_lossy(&header);
+  let request_line = header.lines().next().unwrap_or_default();
+  let mut parts = request_line.split_whitespace();
+  let (Some("CONNECT"), Some(authority)) = (parts.next(), parts.next()) else {
+    stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n").await?;
+    return Err(format!("Unsupported HTTP request: {}", request_line).into());
+  };
+  let (host, port) = authority.rsplit_once(':').ok_or_else(|| format!("Invalid CONNECT authority: {}", authority))?;
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  Ok((host.to_string(), port.parse()?, early_data))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpListener;
+
+  // Function to connect a loopback client, the client writes its whole request before the handshake runs
+  async fn connect(request: &[u8]) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    client.write_all(request).await.unwrap();
+    (client, server)
+  }
+
+  async fn read_reply(client: &mut TcpStream, length: usize) -> Vec<u8> {
+    let mut reply = vec![0u8; length];
+    client.read_exact(&mut reply).await.unwrap();
+    reply
+  }
+
+  fn socks5_request(command: u8, address: &[u8]) -> Vec<u8> {
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, command, 0x00];
+    request.extend_from_slice(address);
+    request.extend_from_slice(&443u16.to_be_bytes());
+    request
+  }
+
+  #[tokio::test]
+  async fn socks5_address_types() {
+    let mut domain = vec![0x03, 11];
+    domain.extend_from_slice(b"example.com");
+    let mut ipv6 = vec![0x04];
+    ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    for (address, host) in [(vec![0x01, 192, 0, 2, 1], "192.0.2.1"), (domain, "example.com"), (ipv6, "::1")] {
+      let (mut client, mut server) = connect(&socks5_request(0x01, &address)).await;
+      let (requested_host, port, early_data) = socks5_handshake(&mut server).await.unwrap();
+      assert_eq!((requested_host.as_str(), port), (host, 443));
+      assert!(early_data.is_empty());
+      assert_eq!(read_reply(&mut client, 2).await, [0x05, 0x00]);
+    }
+  }
+
+  #[tokio::test]
+  async fn socks5_rejects_unsupported_command() {
+    // BIND
+    let (mut client, mut server) = connect(&socks5_request(0x02, &[0x01, 192, 0, 2, 1])).await;
+    assert!(socks5_handshake(&mut server).await.is_err());
+    assert_eq!(read_reply(&mut client, 12).await, [0x05, 0x00, 0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+  }
+
+  #[tokio::test]
+  async fn socks5_rejects_authentication_methods() {
+    // username/password only
+    let (mut client, mut server) = connect(&[0x05, 0x01, 0x02]).await;
+    assert!(socks5_handshake(&mut server).await.is_err());
+    assert_eq!(read_reply(&mut client, 2).await, [0x05, 0xff]);
+  }
+
+  #[tokio::test]
+  async fn http_connect_ipv6_authority() {
+    let (_client, mut server) = connect(b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n").await;
+    let (host, port, early_data) = http_connect_handshake(&mut server).await.unwrap();
+    assert_eq!((host.as_str(), port), ("::1", 443));
+    assert!(early_data.is_empty());
+  }
+
+  #[tokio::test]
+  async fn http_connect_keeps_early_data() {
+    let (_client, mut server) = connect(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n\x16\x03\x01hello").await;
+    let (host, port, early_data) = http_connect_handshake(&mut server).await.unwrap();
+    assert_eq!((host.as_str(), port), ("example.com", 443));
+    assert_eq!(early_data, b"\x16\x03\x01hello");
+  }
+
+  #[tokio::test]
+  async fn http_rejects_other_methods() {
+    let (mut client, mut server) = connect(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+    assert!(http_connect_handshake(&mut server).await.is_err());
+    let reply = b"HTTP/1.1 405 Method Not Allowed\r\n\r\n";
+    assert_eq!(read_reply(&mut client, reply.len()).await, reply);
+  }
+
+  #[tokio::test]
+  async fn http_header_size_is_capped() {
+    let mut request = b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+    request.resize(MAX_HTTP_HEADER_SIZE + 1024, b'a');
+    let (_client, mut server) = connect(&request).await;
+    let e = http_connect_handshake(&mut server).await.unwrap_err();
+    assert_eq!(e.to_string(), "HTTP request header too large");
+  }
+}
@@ -1,16 +1,103 @@
-use crate::hashmap::QUICMAP;
+use crate::hashmap::{remove_connection, BalancePolicy, QuicConnection, QUICMAP};
+use crate::metrics::{
+  BYTES_COPIED, CONNECT_RESULTS, SCEP_VERIFICATIONS, SCEP_VERIFICATION_SECONDS, SESSION_TIMEOUTS, STREAM_OPEN_FAILURES,
+};
+use crate::proxy::OpenMode;
+use crate::reverse::accept_reverse_streams;
+use crate::session::Session;
 use crate::utils::der_to_pem;
 use log::{error, info, warn};
 use serde::Deserialize;
 use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use swp_lib::copy::{self, reset_streams, CopyEnd, CopyLimits, Direction};
+use swp_lib::header::{
+  StreamHeader, Tlv, FLAG_PING, TLV_DESTINATION_ADDRESS, TLV_PROXY_PROTOCOL, TLV_SOURCE_ADDRESS,
+};
+use swp_lib::proxy_protocol;
+use swp_lib::status::{ConnectStatus, StatusFrame};
 use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const TAKEOVER_ERROR_CODE: u32 = 1;
+const PROXY_HEADER_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Default)]
+pub struct StreamOptions {
+  pub rst_on_connect_failure: bool,
+  pub balance: BalancePolicy,
+  pub udp_idle_timeout: Duration,
+  pub reverse_allow: Vec<String>,
+  pub idle_timeout: Option<Duration>,
+  pub max_lifetime: Option<Duration>,
+}
+
+// Client of an accepted connection and the stream header metadata describing it
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+  pub address: SocketAddr,
+  pub metadata: Vec<Tlv>,
+  // Data the client sent right after a proxy handshake, forwarded ahead of the stream
+  pub early_data: Vec<u8>,
+}
+
+// Address sw_connector connects to for an opened port
+//
+// Shared with the accept loop so that it can be updated without reopening the port.
+// Established sessions keep the address they were opened with.
+#[derive(Debug, Clone)]
+pub struct ConnectTarget(Arc<RwLock<(String, u16)>>);
+
+impl ConnectTarget {
+  pub fn new(address: String, port: u16) -> Self {
+    ConnectTarget(Arc::new(RwLock::new((address, port))))
+  }
+
+  pub fn get(&self) -> (String, u16) {
+    self.0.read().unwrap().clone()
+  }
+
+  pub fn set(&self, address: String, port: u16) {
+    *self.0.write().unwrap() = (address, port);
+  }
+}
+
+// What to do when a UID connects while its previous connection still looks alive
+#[derive(Debug, Clone, Copy, Default)]
+pub enum TakeoverPolicy {
+  #[default]
+  Reject,
+  Replace,
+  Probe(Duration),
+  Multiple,
+}
+
+impl TakeoverPolicy {
+  pub fn parse(policy: &str, probe_ms: u64) -> Result<Self, Box<dyn Error>> {
+    match policy {
+      "reject" => Ok(TakeoverPolicy::Reject),
+      "replace" => Ok(TakeoverPolicy::Replace),
+      "probe" => Ok(TakeoverPolicy::Probe(Duration::from_millis(probe_ms))),
+      "multiple" => Ok(TakeoverPolicy::Multiple),
+      _ => Err(format!("Invalid takeover policy: {}", policy).into()),
+    }
+  }
+}
 
 #[derive(Deserialize, Debug)]
 struct User {
   uid: String,
 }
 
-pub async fn handle_quic_connection(conn: quinn::Incoming, scep_url: String) -> Result<(), Box<dyn Error>> {
+pub async fn handle_quic_connection(
+  conn: quinn::Incoming,
+  scep_url: String,
+  takeover: TakeoverPolicy,
+  options: StreamOptions,
+) -> Result<(), Box<dyn Error>> {
   let connection = conn.await.map_err(|e| {
     error!("Failed to establish QUIC connection: {}", e);
     e
@@ -28,12 +115,16 @@ pub async fn handle_quic_connection(conn: quinn::Incoming, scep_url: String) ->
   //
   let encoded = percent_encoding::utf8_percent_encode(&pem_str, percent_encoding::NON_ALPHANUMERIC).to_string();
   let client = reqwest::Client::new();
+  let timer = SCEP_VERIFICATION_SECONDS.start_timer();
   let response = client.get(&scep_url).header("X-Mtls-Clientcert", &encoded).send().await.map_err(|e| {
     error!("Failed to send request to SCEP server: {}", e);
+    SCEP_VERIFICATIONS.with_label_values(&["error"]).inc();
     e
   })?;
+  timer.observe_duration();
   let status = response.status();
   if !status.is_success() {
+    SCEP_VERIFICATIONS.with_label_values(&["rejected"]).inc();
     let body = response.text().await.unwrap_or_else(|_| "Failed to read response body".to_string());
     error!(
       "Failed to verify client certificate. Status: {}, Body: {}",
@@ -41,100 +132,275 @@ pub async fn handle_quic_connection(conn: quinn::Incoming, scep_url: String) ->
     );
     return Err("Failed to verify client certificate".into());
   }
+  SCEP_VERIFICATIONS.with_label_values(&["success"]).inc();
   info!("{} | Successfully verified client certificate", quic_id);
   let body = response.text().await?;
   let u: User = serde_json::from_str(&body)?;
-  let mut map = QUICMAP.write().await;
-  if map.contains_key(&u.uid) && map.get(&u.uid).unwrap().close_reason().is_none() {
-    error!("{} | Connection already exists for UID: {}", quic_id, u.uid);
-    return Err("Connection already exists".into());
+  let existing: Vec<quinn::Connection> = match QUICMAP.read().await.get(&u.uid) {
+    Some(group) => group.live().map(|conn| conn.connection.clone()).collect(),
+    None => Vec::new(),
+  };
+  if !existing.is_empty() {
+    let replace = match takeover {
+      TakeoverPolicy::Reject => false,
+      TakeoverPolicy::Replace => true,
+      TakeoverPolicy::Probe(probe_timeout) => {
+        let mut alive = false;
+        for old in &existing {
+          alive |= probe_connection(old, probe_timeout).await;
+        }
+        !alive
+      }
+      TakeoverPolicy::Multiple => false,
+    };
+    match takeover {
+      TakeoverPolicy::Multiple => {
+        info!("{} | Adding connection {} for UID: {}", quic_id, existing.len() + 1, u.uid);
+      }
+      _ if !replace => {
+        error!("{} | Connection already exists for UID: {}", quic_id, u.uid);
+        return Err("Connection already exists".into());
+      }
+      _ => {
+        for old in &existing {
+          warn!("{} | Replacing connection {} for UID: {}", quic_id, old.stable_id(), u.uid);
+          old.close(TAKEOVER_ERROR_CODE.into(), b"Replaced by new connection");
+        }
+      }
+    }
+  }
+  {
+    // Another connection for the UID may have been added while the existing ones were checked above
+    let mut quicmap = QUICMAP.write().await;
+    let group = quicmap.entry(u.uid.clone()).or_default();
+    match takeover {
+      TakeoverPolicy::Multiple => {}
+      TakeoverPolicy::Replace => {
+        for old in group.live() {
+          warn!("{} | Replacing connection {} for UID: {}", quic_id, old.connection.stable_id(), u.uid);
+          old.connection.close(TAKEOVER_ERROR_CODE.into(), b"Replaced by new connection");
+        }
+      }
+      _ if group.is_live() => {
+        error!("{} | Connection already exists for UID: {}", quic_id, u.uid);
+        return Err("Connection already exists".into());
+      }
+      _ => {}
+    }
+    group.connections.push(QuicConnection::new(connection.clone()));
   }
-  map.insert(u.uid, connection);
+
+  tokio::spawn(accept_reverse_streams(connection.clone(), u.uid.clone(), options));
+  tokio::spawn(async move {
+    let reason = connection.closed().await;
+    info!("{} | QUIC connection for UID {} closed: {}", quic_id, u.uid, reason);
+    remove_connection(&u.uid, quic_id).await;
+  });
 
   Ok(())
 }
 
-pub async fn handle_stream(mut manager_stream: TcpStream, max_vector_size: usize, uid: &String, connect_addrs: String) {
-  let map = QUICMAP.read().await;
-  let connection = if let Some(conn) = map.get(uid) {
-    conn
-  } else {
-    error!("No QUIC connection found for UID: {}", uid);
-    return;
+// Function to check whether a connection still answers, by sending a ping header and waiting for the status frame
+async fn probe_connection(connection: &quinn::Connection, probe_timeout: Duration) -> bool {
+  let probe = async {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut header = StreamHeader::new(&format!("{}-{}", connection.stable_id(), send.id().index()), "", 0);
+    header.flags |= FLAG_PING;
+    header.write_to(&mut send).await?;
+    send.finish()?;
+    StatusFrame::read_from(&mut recv).await?;
+    Ok::<(), Box<dyn Error>>(())
   };
+  match timeout(probe_timeout, probe).await {
+    Ok(Ok(())) => true,
+    Ok(Err(e)) => {
+      warn!("{} | Probe failed: {}", connection.stable_id(), e);
+      false
+    }
+    Err(_) => {
+      warn!("{} | Probe timed out", connection.stable_id());
+      false
+    }
+  }
+}
 
-  let (mut send, mut recv) = match connection.open_bi().await {
-    Ok(streams) => streams,
-    Err(e) => {
-      error!("Failed to open bi stream: {}", e);
-      return;
+// Function to open a bi stream on one of the connections of a UID
+//
+// The connections are tried in balancing order, failing over to the next one when a stream cannot be opened.
+pub async fn open_connector_stream(
+  uid: &str,
+  options: &StreamOptions,
+) -> Option<(QuicConnection, quinn::SendStream, quinn::RecvStream)> {
+  let candidates = match QUICMAP.read().await.get(uid) {
+    Some(group) => group.candidates(options.balance),
+    None => Vec::new(),
+  };
+  if candidates.is_empty() {
+    error!("No QUIC connection found for UID: {}", uid);
+    return None;
+  }
+
+  for quic_connection in candidates {
+    match quic_connection.connection.open_bi().await {
+      Ok((send, recv)) => return Some((quic_connection, send, recv)),
+      Err(e) => {
+        warn!("{} | Failed to open bi stream: {}", quic_connection.connection.stable_id(), e);
+        STREAM_OPEN_FAILURES.inc();
+      }
     }
+  }
+  error!("Failed to open bi stream on any connection for UID: {}", uid);
+  None
+}
+
+pub async fn handle_stream(
+  mut manager_stream: TcpStream,
+  uid: &str,
+  connect_address: &str,
+  connect_port: u16,
+  mode: OpenMode,
+  client: ClientInfo,
+  options: &StreamOptions,
+) {
+  let Some((quic_connection, mut send, mut recv)) = open_connector_stream(uid, options).await else {
+    let _ = mode.reply(&mut manager_stream, &StatusFrame::new(ConnectStatus::Unreachable, "No connector")).await;
+    return;
   };
-  let index = send.id().index();
-  let quic_id = connection.stable_id();
-  let id = format!("{}-{}", quic_id, index);
+  let id = format!("{}-{}", quic_connection.connection.stable_id(), send.id().index());
   info!("{} | Opened bi stream", id);
+  let _stream_guard = quic_connection.stream_guard();
+  let port = manager_stream.local_addr().map(|addr| addr.port()).unwrap_or(0);
+  let destination = format!("{}:{}", connect_address, connect_port);
+  let session = Session::new(&id, uid, port, "tcp", client.address, destination).register();
 
-  if let Err(e) = send_edge_server_address(&mut send, &id, &connect_addrs, max_vector_size).await {
+  let mut header = StreamHeader::new(&id, connect_address, connect_port);
+  header.metadata = client.metadata;
+  if let Err(e) = send_edge_server_address(&mut send, &id, &header).await {
     error!("{} | Failed to send edge server address: {}", id, e);
     return;
   }
   info!("{} | Sent edge server address to agent", id);
+  if !client.early_data.is_empty() {
+    if let Err(e) = send.write_all(&client.early_data).await {
+      error!("{} | Failed to forward data sent during the handshake: {}", id, e);
+      return;
+    }
+  }
 
-  tokio::spawn(async move {
-    if let Err(e) = stream_to_stream_copy(&mut send, &mut recv, &mut manager_stream, &id).await {
-      error!("{} | Stream to stream copy failed: {}", id, e);
+  let frame = match receive_connect_status(&mut recv, &id).await {
+    Ok(frame) => frame,
+    Err(e) => {
+      error!("{} | Failed to receive connect status: {}", id, e);
+      return;
     }
-  });
+  };
+  if let Err(e) = mode.reply(&mut manager_stream, &frame).await {
+    warn!("{} | Failed to send {:?} reply: {}", id, mode, e);
+    return;
+  }
+  if !frame.is_success() {
+    warn!("{} | Agent failed to connect to edge server: {} ({})", id, frame.status, frame.message);
+    if options.rst_on_connect_failure {
+      if let Err(e) = manager_stream.set_zero_linger() {
+        warn!("{} | Failed to set linger on manager stream: {}", id, e);
+      }
+    }
+    return;
+  }
+  let limits = CopyLimits { idle_timeout: options.idle_timeout, max_lifetime: options.max_lifetime };
+  tokio::select! {
+    result = stream_to_stream_copy(&mut send, &mut recv, &mut manager_stream, &id, Some(&session), limits) => {
+      if let Err(e) = result {
+        error!("{} | Stream to stream copy failed: {}", id, e);
+      }
+    }
+    _ = session.terminated() => {
+      info!("{} | Session terminated, resetting streams", id);
+      reset_streams(&mut send, &mut recv, &manager_stream, &id);
+    }
+  }
+}
+
+// Function to describe the client of an accepted connection for the stream header
+//
+// Behind a load balancer the client address is taken from its PROXY protocol header,
+// and None is returned when that header is missing or invalid.
+pub async fn client_info(
+  stream: &mut TcpStream,
+  peer_address: SocketAddr,
+  accept_proxy_protocol: bool,
+  proxy_protocol: Option<u8>,
+) -> Option<ClientInfo> {
+  let mut source = peer_address;
+  let mut destination = stream.local_addr().ok()?;
+  if accept_proxy_protocol {
+    match timeout(Duration::from_secs(PROXY_HEADER_TIMEOUT_SECS), proxy_protocol::read_header(stream)).await {
+      Ok(Ok(Some(addresses))) => {
+        info!("PROXY protocol header from {:?}: client {:?}", peer_address, addresses.0);
+        (source, destination) = addresses;
+      }
+      Ok(Ok(None)) => {}
+      Ok(Err(e)) => {
+        warn!("Invalid PROXY protocol header from {:?}: {}", peer_address, e);
+        return None;
+      }
+      Err(_) => {
+        warn!("Timed out waiting for PROXY protocol header from {:?}", peer_address);
+        return None;
+      }
+    }
+  }
+
+  let mut metadata = Vec::new();
+  if let Some(version) = proxy_protocol {
+    metadata.push(Tlv { kind: TLV_SOURCE_ADDRESS, value: source.to_string().into_bytes() });
+    metadata.push(Tlv { kind: TLV_DESTINATION_ADDRESS, value: destination.to_string().into_bytes() });
+    metadata.push(Tlv { kind: TLV_PROXY_PROTOCOL, value: vec![version] });
+  }
+  Some(ClientInfo { address: source, metadata, early_data: Vec::new() })
 }
 
-async fn send_edge_server_address(
+pub async fn send_edge_server_address(
   send: &mut quinn::SendStream,
   id: &str,
-  connect_addrs: &str,
-  max_vector_size: usize,
+  header: &StreamHeader,
 ) -> Result<(), Box<dyn Error>> {
-  let concatenated = format!("{}|{}", id, connect_addrs);
-  let mut bytes = concatenated.as_bytes().to_vec();
-  bytes.resize(max_vector_size, 0);
-  send.write_all(&bytes).await.map_err(|e| {
-    error!("{} | Failed to write connection id: {}", id, e);
-    e.into()
+  header.write_to(send).await.map_err(|e| {
+    error!("{} | Failed to write stream header: {}", id, e);
+    e
   })
 }
 
-async fn stream_to_stream_copy(
+pub async fn receive_connect_status(recv: &mut quinn::RecvStream, id: &str) -> Result<StatusFrame, Box<dyn Error>> {
+  let frame = StatusFrame::read_from(recv).await?;
+  info!("{} | Received connect status from agent: {}", id, frame.status);
+  CONNECT_RESULTS.with_label_values(&[frame.status.as_str()]).inc();
+  Ok(frame)
+}
+
+// Function to copy both directions between a QUIC stream and the manager stream until both are done
+pub async fn stream_to_stream_copy(
   send: &mut quinn::SendStream,
   recv: &mut quinn::RecvStream,
   manager_stream: &mut TcpStream,
   id: &str,
+  session: Option<&Session>,
+  limits: CopyLimits,
 ) -> Result<(), Box<dyn Error>> {
-  let (mut manager_read, mut manager_write) = manager_stream.split();
-  info!("{} | Stream to stream copy started", id);
-  tokio::select! {
-    recv_result = tokio::io::copy(recv, &mut manager_write) => {
-      match recv_result {
-        Ok(bytes_copied) => {
-          info!("{} | Copied {} bytes from recv to manager stream", id, bytes_copied);
-        }
-        Err(e) => {
-          warn!("{} | Failed to copy from recv to manager stream: {}", id, e);
-          return Err(e.into());
-        }
-      }
-    }
-    send_result = tokio::io::copy(&mut manager_read, send) => {
-      match send_result {
-        Ok(bytes_copied) => {
-          info!("{} | Copied {} bytes from manager stream to send", id, bytes_copied);
-        }
-        Err(e) => {
-          warn!("{} | Failed to copy from manager stream to send: {}", id, e);
-          return Err(e.into());
-        }
-      }
+  let to_connector = BYTES_COPIED.with_label_values(&["to_connector"]);
+  let from_connector = BYTES_COPIED.with_label_values(&["from_connector"]);
+  let count = |direction, bytes| {
+    let (counter, session_bytes) = match direction {
+      Direction::TcpToQuic => (&to_connector, session.map(|session| &session.bytes_to_connector)),
+      Direction::QuicToTcp => (&from_connector, session.map(|session| &session.bytes_from_connector)),
+    };
+    counter.inc_by(bytes);
+    if let Some(session_bytes) = session_bytes {
+      session_bytes.fetch_add(bytes, Ordering::Relaxed);
     }
   };
-  info!("{} | Stream to stream copy completed", id);
+  if let CopyEnd::TimedOut(reason) = copy::stream_to_stream_copy(send, recv, manager_stream, id, limits, count).await? {
+    SESSION_TIMEOUTS.with_label_values(&[reason]).inc();
+  }
   Ok(())
 }
@@ -0,0 +1,78 @@
+use crate::metrics::REVERSE_STREAMS;
+use crate::quic::{stream_to_stream_copy, StreamOptions};
+use log::{error, info, warn};
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use swp_lib::copy::CopyLimits;
+use swp_lib::header::StreamHeader;
+use swp_lib::status::{ConnectStatus, StatusFrame};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+// Function to accept the bi streams sw_connector opens for its reverse ports until the connection is closed
+pub async fn accept_reverse_streams(connection: quinn::Connection, uid: String, options: StreamOptions) {
+  loop {
+    let (send, recv) = match connection.accept_bi().await {
+      Ok(streams) => streams,
+      Err(e) => {
+        info!("{} | Stopped accepting reverse streams for UID {}: {}", connection.stable_id(), uid, e);
+        return;
+      }
+    };
+    let options = options.clone();
+    tokio::spawn(async move {
+      if let Err(e) = handle_reverse_stream(send, recv, &options).await {
+        error!("Reverse stream failed: {}", e);
+      }
+    });
+  }
+}
+
+async fn handle_reverse_stream(
+  mut send: quinn::SendStream,
+  mut recv: quinn::RecvStream,
+  options: &StreamOptions,
+) -> Result<(), Box<dyn Error>> {
+  let header = StreamHeader::read_from(&mut recv).await?;
+  let id = header.id.clone();
+  info!("{} | Received reverse destination from agent: {}", id, header.target());
+
+  let mut target_stream = match connect_target(&header, options).await {
+    Ok(stream) => stream,
+    Err(frame) => {
+      warn!("{} | Failed to connect to reverse destination: {} ({})", id, frame.status, frame.message);
+      REVERSE_STREAMS.with_label_values(&[frame.status.as_str()]).inc();
+      frame.write_to(&mut send).await?;
+      send.finish()?;
+      return Ok(());
+    }
+  };
+  REVERSE_STREAMS.with_label_values(&[ConnectStatus::Success.as_str()]).inc();
+  StatusFrame::new(ConnectStatus::Success, "").write_to(&mut send).await?;
+
+  stream_to_stream_copy(&mut send, &mut recv, &mut target_stream, &id, None, CopyLimits::default()).await
+}
+
+// Only destinations listed in SWL_REVERSE_ALLOW may be reached from sw_connector
+async fn connect_target(header: &StreamHeader, options: &StreamOptions) -> Result<TcpStream, StatusFrame> {
+  let target = header.target();
+  if !options.reverse_allow.contains(&target) {
+    return Err(StatusFrame::new(ConnectStatus::DeniedByPolicy, &format!("{} is not allowed", target)));
+  }
+  match timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS), TcpStream::connect(&target)).await {
+    Ok(Ok(stream)) => Ok(stream),
+    Ok(Err(e)) => {
+      let status = match e.kind() {
+        io::ErrorKind::ConnectionRefused => ConnectStatus::Refused,
+        io::ErrorKind::TimedOut => ConnectStatus::Timeout,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => ConnectStatus::Unreachable,
+        _ => ConnectStatus::Error,
+      };
+      Err(StatusFrame::new(status, &e.to_string()))
+    }
+    Err(_) => Err(StatusFrame::new(ConnectStatus::Timeout, "Connection timed out")),
+  }
+}
@@ -0,0 +1,181 @@
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{sleep, Instant};
+
+const WAIT_INTERVAL_MILLIS: u64 = 100;
+// How long terminated sessions get to reset their streams before their tasks are aborted
+const TERMINATE_WAIT_MILLIS: u64 = 1000;
+
+lazy_static! {
+  static ref SESSIONS: Mutex<HashMap<String, Arc<Session>>> = Mutex::new(HashMap::new());
+}
+
+// Tasks serving the connections and UDP flows of an opened port, so that they can be ended with the port
+#[derive(Debug, Clone, Default)]
+pub struct SessionSet(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl SessionSet {
+  // Function to spawn a session task, forgetting the sessions that already ended
+  pub fn spawn<F>(&self, future: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    let handle = tokio::spawn(future);
+    let mut tasks = self.0.lock().unwrap();
+    tasks.retain(|task| !task.is_finished());
+    tasks.push(handle);
+  }
+
+  pub fn len(&self) -> usize {
+    let mut tasks = self.0.lock().unwrap();
+    tasks.retain(|task| !task.is_finished());
+    tasks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // Function to wait for every session to end, returning false when the timeout elapsed first
+  pub async fn wait(&self, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !self.is_empty() {
+      if Instant::now() >= deadline {
+        return false;
+      }
+      sleep(Duration::from_millis(WAIT_INTERVAL_MILLIS)).await;
+    }
+    true
+  }
+
+  // Function to end every running session, returning how many were still running
+  //
+  // Registered sessions are terminated like DELETE /sessions/{id} so that both peers see a reset,
+  // tasks that are still running afterwards (e.g. in a handshake) are aborted.
+  pub async fn terminate_all(&self) -> usize {
+    let running: Vec<task::Id> = {
+      let mut tasks = self.0.lock().unwrap();
+      tasks.retain(|task| !task.is_finished());
+      tasks.iter().map(|task| task.id()).collect()
+    };
+    for session in SESSIONS.lock().unwrap().values() {
+      if session.task.is_some_and(|id| running.contains(&id)) {
+        session.terminate.notify_one();
+      }
+    }
+    self.wait(Duration::from_millis(TERMINATE_WAIT_MILLIS)).await;
+    for task in self.0.lock().unwrap().drain(..) {
+      task.abort();
+    }
+    running.len()
+  }
+}
+
+// A client tunnelled through an opened port, registered while its stream is open
+#[derive(Debug)]
+pub struct Session {
+  pub id: String,
+  pub uid: String,
+  pub port: u16,
+  pub protocol: &'static str,
+  pub peer_address: SocketAddr,
+  pub destination: String,
+  pub started_at: u64,
+  pub bytes_to_connector: AtomicU64,
+  pub bytes_from_connector: AtomicU64,
+  // Task serving the session, to find the sessions of a SessionSet
+  task: Option<task::Id>,
+  terminate: Notify,
+}
+
+impl Session {
+  pub fn new(
+    id: &str,
+    uid: &str,
+    port: u16,
+    protocol: &'static str,
+    peer_address: SocketAddr,
+    destination: String,
+  ) -> Self {
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Session {
+      id: id.to_string(),
+      uid: uid.to_string(),
+      port,
+      protocol,
+      peer_address,
+      destination,
+      started_at,
+      bytes_to_connector: AtomicU64::new(0),
+      bytes_from_connector: AtomicU64::new(0),
+      task: task::try_id(),
+      terminate: Notify::new(),
+    }
+  }
+
+  // Function to add the session to the registry until the returned guard is dropped
+  pub fn register(self) -> SessionGuard {
+    let session = Arc::new(self);
+    SESSIONS.lock().unwrap().insert(session.id.clone(), session.clone());
+    SessionGuard(session)
+  }
+
+  // Resolves once the session has been terminated through the API
+  pub async fn terminated(&self) {
+    self.terminate.notified().await
+  }
+
+  pub fn to_json(&self) -> Value {
+    json!({
+      "id": self.id,
+      "uid": self.uid,
+      "port": self.port,
+      "protocol": self.protocol,
+      "peer_address": self.peer_address.to_string(),
+      "destination": self.destination,
+      "started_at": self.started_at,
+      "bytes_to_connector": self.bytes_to_connector.load(Ordering::Relaxed),
+      "bytes_from_connector": self.bytes_from_connector.load(Ordering::Relaxed)
+    })
+  }
+}
+
+pub struct SessionGuard(Arc<Session>);
+
+impl Deref for SessionGuard {
+  type Target = Session;
+
+  fn deref(&self) -> &Session {
+    &self.0
+  }
+}
+
+impl Drop for SessionGuard {
+  fn drop(&mut self) {
+    SESSIONS.lock().unwrap().remove(&self.0.id);
+  }
+}
+
+pub fn sessions() -> Vec<Arc<Session>> {
+  SESSIONS.lock().unwrap().values().cloned().collect()
+}
+
+// Function to ask a session to reset its streams, returning false when no session has the id
+pub fn terminate(id: &str) -> bool {
+  match SESSIONS.lock().unwrap().get(id) {
+    Some(session) => {
+      session.terminate.notify_one();
+      true
+    }
+    None => false,
+  }
+}
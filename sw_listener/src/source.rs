@@ -0,0 +1,84 @@
+use ipnet::IpNet;
+use std::error::Error;
+use std::net::IpAddr;
+
+// Source addresses an opened port accepts connections from
+//
+// A source is rejected when it is inside any deny network. When allow networks are given,
+// it must also be inside at least one of them. Entries accept CIDRs and plain IP addresses.
+#[derive(Debug, Default)]
+pub struct SourceFilter {
+  allow: Vec<IpNet>,
+  deny: Vec<IpNet>,
+}
+
+impl SourceFilter {
+  pub fn new(allow: &[String], deny: &[String]) -> Result<Self, Box<dyn Error>> {
+    Ok(SourceFilter {
+      allow: allow.iter().map(|entry| parse_network(entry)).collect::<Result<_, _>>()?,
+      deny: deny.iter().map(|entry| parse_network(entry)).collect::<Result<_, _>>()?,
+    })
+  }
+
+  pub fn allows(&self, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    if self.deny.iter().any(|network| network.contains(&ip)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|network| network.contains(&ip))
+  }
+}
+
+fn parse_network(entry: &str) -> Result<IpNet, Box<dyn Error>> {
+  if let Ok(network) = entry.parse::<IpNet>() {
+    Ok(network)
+  } else if let Ok(ip) = entry.parse::<IpAddr>() {
+    Ok(IpNet::from(ip))
+  } else {
+    Err(format!("Invalid source CIDR: {}", entry).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filter(allow: &[&str], deny: &[&str]) -> SourceFilter {
+    let strings = |entries: &[&str]| entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>();
+    SourceFilter::new(&strings(allow), &strings(deny)).unwrap()
+  }
+
+  fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+  }
+
+  #[test]
+  fn empty_allow_list_allows_everything() {
+    let filter = filter(&[], &[]);
+    assert!(filter.allows(ip("192.0.2.1")));
+    assert!(filter.allows(ip("2001:db8::1")));
+  }
+
+  #[test]
+  fn deny_beats_allow() {
+    let filter = filter(&["10.0.0.0/8"], &["10.1.0.0/16", "10.2.3.4"]);
+    assert!(filter.allows(ip("10.0.0.1")));
+    assert!(!filter.allows(ip("10.1.2.3")));
+    assert!(!filter.allows(ip("10.2.3.4")));
+    assert!(!filter.allows(ip("192.0.2.1")));
+  }
+
+  #[test]
+  fn ipv4_mapped_peer_matches_ipv4_network() {
+    let filter = filter(&["192.0.2.0/24"], &["192.0.2.100"]);
+    assert!(filter.allows(ip("::ffff:192.0.2.1")));
+    assert!(!filter.allows(ip("::ffff:192.0.2.100")));
+    assert!(!filter.allows(ip("::ffff:198.51.100.1")));
+  }
+
+  #[test]
+  fn rejects_invalid_entries() {
+    assert!(SourceFilter::new(&["10.0.0.0/33".to_string()], &[]).is_err());
+    assert!(SourceFilter::new(&[], &["example.com".to_string()]).is_err());
+  }
+}
@@ -0,0 +1,37 @@
+use log::{error, info};
+use serde::{de::DeserializeOwned, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Function to load persisted entries from a JSON file, returning nothing if the file does not exist yet
+pub fn load<T: DeserializeOwned>(path: &str) -> Result<Vec<T>, Box<dyn Error>> {
+  let contents = match fs::read_to_string(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      info!("Store file not found, starting empty: {}", path);
+      return Ok(Vec::new());
+    }
+    Err(e) => {
+      error!("Failed to read store file {}: {}", path, e);
+      return Err(e.into());
+    }
+  };
+  let entries: Vec<T> = serde_json::from_str(&contents)?;
+  Ok(entries)
+}
+
+// Function to rewrite the JSON file with the given entries, replacing it atomically
+pub fn save<T: Serialize>(path: &str, entries: &[T]) -> Result<(), Box<dyn Error>> {
+  let contents = serde_json::to_string_pretty(entries)?;
+  let tmp_path = format!("{}.tmp", path);
+  if let Some(parent) = Path::new(path).parent() {
+    if !parent.as_os_str().is_empty() {
+      fs::create_dir_all(parent)?;
+    }
+  }
+  fs::write(&tmp_path, contents)?;
+  fs::rename(&tmp_path, path)?;
+  Ok(())
+}
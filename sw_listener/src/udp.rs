@@ -0,0 +1,209 @@
+use crate::metrics::{ACCEPTED_CONNECTIONS, BYTES_COPIED, REJECTED_CONNECTIONS, SESSION_TIMEOUTS, UDP_FLOWS};
+use crate::quic::{
+  open_connector_stream, receive_connect_status, send_edge_server_address, ConnectTarget, StreamOptions,
+};
+use crate::session::{Session, SessionSet};
+use crate::source::SourceFilter;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use swp_lib::copy::STREAM_RESET_CODE;
+use swp_lib::datagram::{read_datagram, write_datagram, MAX_DATAGRAM_SIZE};
+use swp_lib::header::{StreamHeader, FLAG_UDP, TLV_IDLE_TIMEOUT, TLV_MAX_LIFETIME};
+use swp_lib::idle::IdleTimer;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+const FLOW_QUEUE_SIZE: usize = 64;
+
+type FlowMap = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+// Function to receive datagrams on an opened UDP port and forward every peer address as its own flow
+pub async fn run_udp_listener(
+  socket: UdpSocket,
+  uid: String,
+  target: ConnectTarget,
+  sources: Arc<SourceFilter>,
+  sessions: SessionSet,
+  options: StreamOptions,
+) {
+  let socket = Arc::new(socket);
+  let port = socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+  let flows: FlowMap = Arc::new(Mutex::new(HashMap::new()));
+  let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+  loop {
+    let (len, peer_address) = match socket.recv_from(&mut buf).await {
+      Ok(received) => received,
+      Err(e) => {
+        warn!("Failed to receive datagram on port {}: {}", port, e);
+        continue;
+      }
+    };
+    let payload = buf[..len].to_vec();
+
+    let mut flow_map = flows.lock().unwrap();
+    if let Some(tx) = flow_map.get(&peer_address) {
+      if tx.try_send(payload).is_err() {
+        warn!("Dropped datagram from {:?}: flow queue is full", peer_address);
+      }
+      continue;
+    }
+
+    if !sources.allows(peer_address.ip()) {
+      warn!("Rejected datagram from {:?} on port {}", peer_address, port);
+      REJECTED_CONNECTIONS.with_label_values(&[&port.to_string()]).inc();
+      continue;
+    }
+    info!("New UDP flow from: {:?}", peer_address);
+    ACCEPTED_CONNECTIONS.with_label_values(&[&port.to_string()]).inc();
+    let (tx, rx) = mpsc::channel(FLOW_QUEUE_SIZE);
+    let _ = tx.try_send(payload);
+    flow_map.insert(peer_address, tx);
+    drop(flow_map);
+
+    let (connect_address, connect_port) = target.get();
+    let flow = Flow {
+      socket: socket.clone(),
+      peer_address,
+      uid: uid.clone(),
+      connect_address,
+      connect_port,
+    };
+    let flow_guard = FlowGuard::new(flows.clone(), peer_address);
+    let options = options.clone();
+    sessions.spawn(async move {
+      let _flow_guard = flow_guard;
+      flow.run(rx, &options).await;
+    });
+  }
+}
+
+// Counts a flow in swl_udp_flows until dropped, even when its task is aborted by closing the port
+struct FlowGuard {
+  flows: FlowMap,
+  peer_address: SocketAddr,
+}
+
+impl FlowGuard {
+  fn new(flows: FlowMap, peer_address: SocketAddr) -> Self {
+    UDP_FLOWS.inc();
+    FlowGuard { flows, peer_address }
+  }
+}
+
+impl Drop for FlowGuard {
+  fn drop(&mut self) {
+    self.flows.lock().unwrap().remove(&self.peer_address);
+    UDP_FLOWS.dec();
+  }
+}
+
+struct Flow {
+  socket: Arc<UdpSocket>,
+  peer_address: SocketAddr,
+  uid: String,
+  connect_address: String,
+  connect_port: u16,
+}
+
+impl Flow {
+  async fn run(&self, mut rx: mpsc::Receiver<Vec<u8>>, options: &StreamOptions) {
+    let Some((quic_connection, mut send, mut recv)) = open_connector_stream(&self.uid, options).await else {
+      return;
+    };
+    let id = format!("{}-{}", quic_connection.connection.stable_id(), send.id().index());
+    info!("{} | Opened bi stream for UDP flow from {:?}", id, self.peer_address);
+    let _stream_guard = quic_connection.stream_guard();
+    let port = self.socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    let destination = format!("{}:{}", self.connect_address, self.connect_port);
+    let session = Session::new(&id, &self.uid, port, "udp", self.peer_address, destination).register();
+
+    let mut header = StreamHeader::new(&id, &self.connect_address, self.connect_port);
+    header.flags |= FLAG_UDP;
+    // sw_connector expires the flow after its own udp_idle_timeout_secs unless the port sets its limits
+    if let Some(idle_timeout) = options.idle_timeout {
+      header.push_duration(TLV_IDLE_TIMEOUT, idle_timeout);
+    }
+    if let Some(max_lifetime) = options.max_lifetime {
+      header.push_duration(TLV_MAX_LIFETIME, max_lifetime);
+    }
+    if let Err(e) = send_edge_server_address(&mut send, &id, &header).await {
+      error!("{} | Failed to send edge server address: {}", id, e);
+      return;
+    }
+    match receive_connect_status(&mut recv, &id).await {
+      Ok(frame) if frame.is_success() => {}
+      Ok(frame) => {
+        warn!("{} | Agent failed to open UDP flow: {} ({})", id, frame.status, frame.message);
+        return;
+      }
+      Err(e) => {
+        error!("{} | Failed to receive connect status: {}", id, e);
+        return;
+      }
+    }
+
+    let idle = IdleTimer::new(options.udp_idle_timeout);
+    let to_connector = async {
+      while let Some(payload) = rx.recv().await {
+        idle.touch();
+        write_datagram(&mut send, &payload).await?;
+        BYTES_COPIED.with_label_values(&["to_connector"]).inc_by(payload.len() as u64);
+        session.bytes_to_connector.fetch_add(payload.len() as u64, Ordering::Relaxed);
+      }
+      Ok::<(), Box<dyn Error>>(())
+    };
+    let from_connector = async {
+      loop {
+        let payload = read_datagram(&mut recv).await?;
+        let Some(payload) = payload else {
+          break;
+        };
+        idle.touch();
+        self.socket.send_to(&payload, self.peer_address).await?;
+        BYTES_COPIED.with_label_values(&["from_connector"]).inc_by(payload.len() as u64);
+        session.bytes_from_connector.fetch_add(payload.len() as u64, Ordering::Relaxed);
+      }
+      Ok::<(), Box<dyn Error>>(())
+    };
+    let lifetime_expired = async {
+      match options.max_lifetime {
+        Some(max_lifetime) => sleep(max_lifetime).await,
+        None => future::pending().await,
+      }
+    };
+    tokio::select! {
+      result = to_connector => {
+        if let Err(e) = result {
+          warn!("{} | Failed to forward datagram to agent: {}", id, e);
+        }
+      }
+      result = from_connector => {
+        if let Err(e) = result {
+          warn!("{} | Failed to forward datagram from agent: {}", id, e);
+        }
+      }
+      _ = idle.expired() => {
+        info!("{} | UDP flow from {:?} expired after {:?} idle", id, self.peer_address, options.udp_idle_timeout);
+        SESSION_TIMEOUTS.with_label_values(&["idle"]).inc();
+      }
+      _ = lifetime_expired => {
+        let max_lifetime = options.max_lifetime.unwrap_or_default();
+        info!("{} | UDP flow from {:?} reached its max lifetime of {:?}, closing", id, self.peer_address, max_lifetime);
+        SESSION_TIMEOUTS.with_label_values(&["lifetime"]).inc();
+      }
+      _ = session.terminated() => {
+        info!("{} | Session terminated, resetting stream", id);
+        let _ = send.reset(STREAM_RESET_CODE.into());
+        let _ = recv.stop(STREAM_RESET_CODE.into());
+        return;
+      }
+    }
+    let _ = send.finish();
+  }
+}
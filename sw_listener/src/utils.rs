@@ -24,7 +24,7 @@ pub fn pem_to_der(pem_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
 
 // Function to load private key from file and convert to DER
 pub fn key_to_der(key_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-  let key_str = String::from_utf8_lossy(&key_data);
+  let key_str = String::from_utf8_lossy(key_data);
   let key_lines: Vec<&str> = key_str.lines().collect();
   let key_base64: String = key_lines.into_iter().filter(|line| !line.starts_with("-----")).collect();
   let der_data = general_purpose::STANDARD.decode(&key_base64)?;
@@ -33,11 +33,10 @@ pub fn key_to_der(key_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
 
 // Function to get environment variable with default value
 pub fn get_env(key: &str, default: &str) -> String {
-  let env = match std::env::var(key) {
+  match std::env::var(key) {
     Ok(val) => val,
     Err(_) => default.to_string(),
-  };
-  return env;
+  }
 }
 
 pub fn read_file(path: &str, error_msg: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -49,4 +48,4 @@ pub fn read_file(path: &str, error_msg: &str) -> Result<Vec<u8>, Box<dyn std::er
     }
     e.into()
   })
-}
\ No newline at end of file
+}
@@ -0,0 +1,250 @@
+use crate::idle::IdleTimer;
+use log::{info, warn};
+use std::future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+//
+// Full-duplex copy between a QUIC bi stream and a TCP connection, shared by sw_listener and sw_connector
+//
+// EOF on one side is passed on as a half-close (QUIC stream finish or TCP shutdown) while the other
+// direction keeps running, until both directions are done. A reset or error on either side, or an
+// expired idle timeout or max lifetime, resets both sides so that neither peer mistakes it for a clean close.
+//
+// Application error code used to reset and stop QUIC streams
+pub const STREAM_RESET_CODE: u32 = 1;
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  QuicToTcp,
+  TcpToQuic,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyLimits {
+  pub idle_timeout: Option<Duration>,
+  pub max_lifetime: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyEnd {
+  Finished,
+  // The session was reset after its idle timeout ("idle") or max lifetime ("lifetime")
+  TimedOut(&'static str),
+}
+
+// Function to copy both directions until both are done, calling on_copied for every chunk written
+pub async fn stream_to_stream_copy<F>(
+  send: &mut quinn::SendStream,
+  recv: &mut quinn::RecvStream,
+  stream: &mut TcpStream,
+  id: &str,
+  limits: CopyLimits,
+  on_copied: F,
+) -> io::Result<CopyEnd>
+where
+  F: Fn(Direction, u64),
+{
+  let idle = limits.idle_timeout.map(IdleTimer::new);
+  let result = {
+    let (mut tcp_read, mut tcp_write) = stream.split();
+    info!("{} | Stream to stream copy started", id);
+    let quic_to_tcp = async {
+      let counted = |n| on_copied(Direction::QuicToTcp, n);
+      let bytes_copied = copy_half(&mut *recv, &mut tcp_write, idle.as_ref(), counted).await?;
+      tcp_write.shutdown().await?;
+      info!("{} | Copied {} bytes from QUIC stream to TCP stream", id, bytes_copied);
+      Ok::<(), io::Error>(())
+    };
+    let tcp_to_quic = async {
+      let counted = |n| on_copied(Direction::TcpToQuic, n);
+      let bytes_copied = copy_half(&mut tcp_read, &mut *send, idle.as_ref(), counted).await?;
+      send.finish()?;
+      info!("{} | Copied {} bytes from TCP stream to QUIC stream", id, bytes_copied);
+      Ok::<(), io::Error>(())
+    };
+    let idle_expired = async {
+      match &idle {
+        Some(idle) => idle.expired().await,
+        None => future::pending().await,
+      }
+    };
+    let lifetime_expired = async {
+      match limits.max_lifetime {
+        Some(max_lifetime) => sleep(max_lifetime).await,
+        None => future::pending().await,
+      }
+    };
+    tokio::select! {
+      result = async { tokio::try_join!(quic_to_tcp, tcp_to_quic) } => result.map(|_| CopyEnd::Finished),
+      _ = idle_expired => {
+        info!("{} | No data for {:?}, closing session", id, limits.idle_timeout.unwrap_or_default());
+        Ok(CopyEnd::TimedOut("idle"))
+      }
+      _ = lifetime_expired => {
+        info!("{} | Session reached its max lifetime of {:?}, closing", id, limits.max_lifetime.unwrap_or_default());
+        Ok(CopyEnd::TimedOut("lifetime"))
+      }
+    }
+  };
+  match result {
+    Ok(CopyEnd::Finished) => info!("{} | Stream to stream copy finished", id),
+    Ok(CopyEnd::TimedOut(_)) => reset_streams(send, recv, stream, id),
+    Err(ref e) => {
+      warn!("{} | Stream to stream copy failed, resetting both sides: {}", id, e);
+      reset_streams(send, recv, stream, id);
+    }
+  }
+  result
+}
+
+async fn copy_half<R, W, F>(reader: &mut R, writer: &mut W, idle: Option<&IdleTimer>, on_copied: F) -> io::Result<u64>
+where
+  R: AsyncRead + Unpin,
+  W: AsyncWrite + Unpin,
+  F: Fn(u64),
+{
+  let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+  let mut bytes_copied = 0;
+  loop {
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(bytes_copied);
+    }
+    if let Some(idle) = idle {
+      idle.touch();
+    }
+    writer.write_all(&buf[..n]).await?;
+    on_copied(n as u64);
+    bytes_copied += n as u64;
+  }
+}
+
+// Function to reset both the QUIC stream and the TCP connection so that neither peer mistakes it for a clean close
+pub fn reset_streams(send: &mut quinn::SendStream, recv: &mut quinn::RecvStream, stream: &TcpStream, id: &str) {
+  let _ = send.reset(STREAM_RESET_CODE.into());
+  let _ = recv.stop(STREAM_RESET_CODE.into());
+  if let Err(e) = stream.set_zero_linger() {
+    warn!("{} | Failed to set linger on TCP stream: {}", id, e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+  use quinn::rustls::RootCertStore;
+  use quinn::{ClientConfig, Endpoint, ReadError, ReadToEndError, ServerConfig};
+  use std::sync::Arc;
+  use tokio::net::TcpListener;
+  use tokio::task::JoinHandle;
+
+  // A QUIC stream copied to a TCP connection, the peer ends are driven by the test
+  struct Session {
+    peer_send: quinn::SendStream,
+    peer_recv: quinn::RecvStream,
+    peer_stream: TcpStream,
+    copy: JoinHandle<io::Result<CopyEnd>>,
+    // Dropping every handle of a connection closes it, which would hide the stream resets from the peer
+    _connections: (quinn::Connection, quinn::Connection),
+    _endpoints: (Endpoint, Endpoint),
+  }
+
+  async fn start(limits: CopyLimits) -> Session {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let server_config = ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
+    let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(Arc::new(roots)).unwrap());
+
+    let connecting = client.connect(server.local_addr().unwrap(), "localhost").unwrap();
+    let (client_connection, server_connection) = tokio::join!(connecting, async {
+      server.accept().await.unwrap().await
+    });
+    let (client_connection, server_connection) = (client_connection.unwrap(), server_connection.unwrap());
+    let (mut peer_send, peer_recv) = client_connection.open_bi().await.unwrap();
+    // The stream is only announced to the server once data is sent on it
+    peer_send.write_all(b"hello").await.unwrap();
+    let (mut send, mut recv) = server_connection.accept_bi().await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let copy = tokio::spawn(async move {
+      stream_to_stream_copy(&mut send, &mut recv, &mut stream, "test", limits, |_, _| {}).await
+    });
+    let _connections = (client_connection, server_connection);
+    Session { peer_send, peer_recv, peer_stream, copy, _connections, _endpoints: (server, client) }
+  }
+
+  async fn read_exact(stream: &mut TcpStream, length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    stream.read_exact(&mut bytes).await.unwrap();
+    bytes
+  }
+
+  fn is_reset(result: Result<Vec<u8>, ReadToEndError>) -> bool {
+    matches!(result, Err(ReadToEndError::Read(ReadError::Reset(code))) if code == STREAM_RESET_CODE.into())
+  }
+
+  #[tokio::test]
+  async fn half_close_keeps_other_direction() {
+    let mut session = start(CopyLimits::default()).await;
+    assert_eq!(read_exact(&mut session.peer_stream, 5).await, b"hello");
+
+    // TCP shutdown is passed on as a QUIC finish
+    session.peer_stream.write_all(b"request").await.unwrap();
+    session.peer_stream.shutdown().await.unwrap();
+    assert_eq!(session.peer_recv.read_to_end(1024).await.unwrap(), b"request");
+
+    // while QUIC to TCP keeps flowing until the QUIC side finishes too
+    session.peer_send.write_all(b"response").await.unwrap();
+    assert_eq!(read_exact(&mut session.peer_stream, 8).await, b"response");
+    session.peer_send.finish().unwrap();
+    let mut rest = Vec::new();
+    session.peer_stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(session.copy.await.unwrap().unwrap(), CopyEnd::Finished);
+  }
+
+  #[tokio::test]
+  async fn quic_reset_resets_tcp() {
+    let mut session = start(CopyLimits::default()).await;
+    assert_eq!(read_exact(&mut session.peer_stream, 5).await, b"hello");
+    session.peer_send.reset(STREAM_RESET_CODE.into()).unwrap();
+    assert!(session.copy.await.unwrap().is_err());
+    let e = session.peer_stream.read(&mut [0u8; 16]).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    assert!(is_reset(session.peer_recv.read_to_end(1024).await));
+  }
+
+  #[tokio::test]
+  async fn tcp_reset_resets_quic() {
+    let session = start(CopyLimits::default()).await;
+    let Session { mut peer_recv, mut peer_stream, copy, _connections, _endpoints, .. } = session;
+    assert_eq!(read_exact(&mut peer_stream, 5).await, b"hello");
+    peer_stream.set_zero_linger().unwrap();
+    drop(peer_stream);
+    assert!(copy.await.unwrap().is_err());
+    assert!(is_reset(peer_recv.read_to_end(1024).await));
+  }
+
+  #[tokio::test]
+  async fn idle_timeout_resets_both_sides() {
+    let limits = CopyLimits { idle_timeout: Some(Duration::from_millis(200)), max_lifetime: None };
+    let mut session = start(limits).await;
+    assert_eq!(read_exact(&mut session.peer_stream, 5).await, b"hello");
+    assert_eq!(session.copy.await.unwrap().unwrap(), CopyEnd::TimedOut("idle"));
+    let e = session.peer_stream.read(&mut [0u8; 16]).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    assert!(is_reset(session.peer_recv.read_to_end(1024).await));
+  }
+}
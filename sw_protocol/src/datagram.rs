@@ -0,0 +1,60 @@
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//
+// Datagram frame carried on the bi stream of a UDP flow
+//
+// +--------+---------+
+// | length | payload |
+// | 2      | length  |
+// +--------+---------+
+//
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+pub async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+  if payload.len() > MAX_DATAGRAM_SIZE {
+    return Err(format!("Datagram too large: {} bytes", payload.len()).into());
+  }
+  let mut bytes = Vec::with_capacity(2 + payload.len());
+  bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+  bytes.extend_from_slice(payload);
+  writer.write_all(&bytes).await?;
+  Ok(())
+}
+
+// Returns None when the stream was finished between two frames
+pub async fn read_datagram<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+  let mut length = [0u8; 2];
+  match reader.read_exact(&mut length).await {
+    Ok(_) => {}
+    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e.into()),
+  }
+  let mut payload = vec![0u8; u16::from_be_bytes(length) as usize];
+  reader.read_exact(&mut payload).await?;
+  Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn round_trip_until_finish() {
+    let mut bytes = Vec::new();
+    write_datagram(&mut bytes, b"first").await.unwrap();
+    write_datagram(&mut bytes, b"").await.unwrap();
+    let mut reader = &bytes[..];
+    assert_eq!(read_datagram(&mut reader).await.unwrap(), Some(b"first".to_vec()));
+    assert_eq!(read_datagram(&mut reader).await.unwrap(), Some(Vec::new()));
+    assert_eq!(read_datagram(&mut reader).await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn rejects_oversized_and_truncated() {
+    let mut bytes = Vec::new();
+    assert!(write_datagram(&mut bytes, &vec![0u8; MAX_DATAGRAM_SIZE + 1]).await.is_err());
+    let truncated = [0x00, 0x04, b'a'];
+    assert!(read_datagram(&mut &truncated[..]).await.is_err());
+  }
+}
@@ -0,0 +1,283 @@
+use std::error::Error;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//
+// Stream header sent by sw_listener at the start of every bi stream
+//
+// +-------+---------+-------+--------+--------------------------------------------+
+// | magic | version | flags | length | body (length bytes)                        |
+// | 4     | 1       | 1     | 2      | id, host, port, metadata TLVs              |
+// +-------+---------+-------+--------+--------------------------------------------+
+//
+// id and host are encoded as a 2-byte length followed by UTF-8 bytes, port as 2 bytes,
+// and each metadata TLV as a 1-byte type, a 2-byte length and the value. All integers are big endian.
+//
+pub const MAGIC: [u8; 4] = *b"SWRP";
+pub const PROTOCOL_VERSION: u8 = 1;
+const PREFIX_SIZE: usize = 8;
+
+// Liveness probe: sw_connector answers with a status frame without connecting anywhere
+pub const FLAG_PING: u8 = 0x01;
+// UDP flow: both sides exchange datagram frames instead of a byte stream after the status frame
+pub const FLAG_UDP: u8 = 0x02;
+
+// Socket address of the client that connected to sw_listener, as a UTF-8 string
+pub const TLV_SOURCE_ADDRESS: u8 = 0x01;
+// Socket address the client connected to on sw_listener, as a UTF-8 string
+pub const TLV_DESTINATION_ADDRESS: u8 = 0x02;
+// PROXY protocol version (1 byte, 1 or 2) sw_connector writes to the target before any data
+pub const TLV_PROXY_PROTOCOL: u8 = 0x03;
+// Idle timeout of a UDP flow in seconds (8 bytes), used by sw_connector instead of its udp_idle_timeout_secs
+pub const TLV_IDLE_TIMEOUT: u8 = 0x04;
+// Max lifetime of a UDP flow in seconds (8 bytes)
+pub const TLV_MAX_LIFETIME: u8 = 0x05;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+  pub kind: u8,
+  pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamHeader {
+  pub version: u8,
+  pub flags: u8,
+  pub id: String,
+  pub host: String,
+  pub port: u16,
+  pub metadata: Vec<Tlv>,
+}
+
+impl StreamHeader {
+  pub fn new(id: &str, host: &str, port: u16) -> Self {
+    StreamHeader {
+      version: PROTOCOL_VERSION,
+      flags: 0,
+      id: id.to_string(),
+      host: host.to_string(),
+      port,
+      metadata: Vec::new(),
+    }
+  }
+
+  pub fn has_flag(&self, flag: u8) -> bool {
+    self.flags & flag != 0
+  }
+
+  pub fn get_metadata(&self, kind: u8) -> Option<&[u8]> {
+    self.metadata.iter().find(|tlv| tlv.kind == kind).map(|tlv| tlv.value.as_slice())
+  }
+
+  pub fn push_duration(&mut self, kind: u8, duration: Duration) {
+    self.metadata.push(Tlv { kind, value: duration.as_secs().to_be_bytes().to_vec() });
+  }
+
+  // Duration of a TLV written by push_duration, None when missing or malformed
+  pub fn get_duration(&self, kind: u8) -> Option<Duration> {
+    let secs = self.get_metadata(kind)?.try_into().ok().map(u64::from_be_bytes)?;
+    Some(Duration::from_secs(secs))
+  }
+
+  // Address string of the target, with brackets around IPv6 literals
+  pub fn target(&self) -> String {
+    if self.host.contains(':') {
+      format!("[{}]:{}", self.host, self.port)
+    } else {
+      format!("{}:{}", self.host, self.port)
+    }
+  }
+
+  pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut body = Vec::new();
+    put_bytes(&mut body, self.id.as_bytes())?;
+    put_bytes(&mut body, self.host.as_bytes())?;
+    body.extend_from_slice(&self.port.to_be_bytes());
+    for tlv in &self.metadata {
+      body.push(tlv.kind);
+      put_bytes(&mut body, &tlv.value)?;
+    }
+    let length: u16 = body.len().try_into().map_err(|_| "Stream header too large")?;
+
+    let mut bytes = Vec::with_capacity(PREFIX_SIZE + body.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(self.version);
+    bytes.push(self.flags);
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+  }
+
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+    let bytes = self.encode()?;
+    writer.write_all(&bytes).await?;
+    Ok(())
+  }
+
+  pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    reader.read_exact(&mut prefix).await?;
+    let (version, flags, length) = parse_prefix(&prefix)?;
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    parse_body(version, flags, &body)
+  }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, value: &[u8]) -> Result<(), Box<dyn Error>> {
+  let length: u16 = value.len().try_into().map_err(|_| "Stream header field too large")?;
+  buf.extend_from_slice(&length.to_be_bytes());
+  buf.extend_from_slice(value);
+  Ok(())
+}
+
+fn parse_prefix(prefix: &[u8]) -> Result<(u8, u8, usize), Box<dyn Error>> {
+  if prefix[..4] != MAGIC {
+    return Err("Invalid stream header magic".into());
+  }
+  let version = prefix[4];
+  if version == 0 || version > PROTOCOL_VERSION {
+    return Err(format!("Unsupported stream header version: {}", version).into());
+  }
+  let length = u16::from_be_bytes([prefix[6], prefix[7]]) as usize;
+  Ok((version, prefix[5], length))
+}
+
+fn parse_body(version: u8, flags: u8, body: &[u8]) -> Result<StreamHeader, Box<dyn Error>> {
+  let mut reader = Reader { buf: body, pos: 0 };
+  let id = String::from_utf8(reader.bytes()?.to_vec())?;
+  let host = String::from_utf8(reader.bytes()?.to_vec())?;
+  let port = reader.u16()?;
+  let mut metadata = Vec::new();
+  while !reader.is_empty() {
+    let kind = reader.u8()?;
+    let value = reader.bytes()?.to_vec();
+    metadata.push(Tlv { kind, value });
+  }
+  Ok(StreamHeader { version, flags, id, host, port, metadata })
+}
+
+struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn is_empty(&self) -> bool {
+    self.pos >= self.buf.len()
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+    if self.buf.len() - self.pos < n {
+      return Err("Stream header truncated".into());
+    }
+    let slice = &self.buf[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(slice)
+  }
+
+  fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn bytes(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+    let length = self.u16()? as usize;
+    self.take(length)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header() -> StreamHeader {
+    let mut header = StreamHeader::new("1-0", "example.com", 443);
+    header.flags = FLAG_UDP;
+    header.metadata.push(Tlv { kind: TLV_SOURCE_ADDRESS, value: b"192.0.2.1:5000".to_vec() });
+    header.metadata.push(Tlv { kind: TLV_PROXY_PROTOCOL, value: vec![2] });
+    header
+  }
+
+  async fn read(bytes: &[u8]) -> Result<StreamHeader, Box<dyn Error>> {
+    StreamHeader::read_from(&mut &bytes[..]).await
+  }
+
+  #[tokio::test]
+  async fn round_trip_with_metadata() {
+    let bytes = header().encode().unwrap();
+    assert_eq!(&bytes[..4], &MAGIC);
+    let decoded = read(&bytes).await.unwrap();
+    assert_eq!(decoded, header());
+    assert!(decoded.has_flag(FLAG_UDP));
+    assert!(!decoded.has_flag(FLAG_PING));
+    assert_eq!(decoded.get_metadata(TLV_PROXY_PROTOCOL), Some(&[2u8][..]));
+    assert_eq!(decoded.get_metadata(TLV_DESTINATION_ADDRESS), None);
+  }
+
+  #[tokio::test]
+  async fn read_leaves_stream_data() {
+    let mut bytes = header().encode().unwrap();
+    bytes.extend_from_slice(b"payload");
+    let mut reader = &bytes[..];
+    StreamHeader::read_from(&mut reader).await.unwrap();
+    assert_eq!(reader, b"payload");
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_magic() {
+    let mut bytes = header().encode().unwrap();
+    bytes[0] = b'X';
+    let e = read(&bytes).await.unwrap_err();
+    assert!(e.to_string().contains("magic"), "{}", e);
+  }
+
+  #[tokio::test]
+  async fn rejects_unsupported_version() {
+    for version in [0, PROTOCOL_VERSION + 1] {
+      let mut bytes = header().encode().unwrap();
+      bytes[4] = version;
+      let e = read(&bytes).await.unwrap_err();
+      assert!(e.to_string().contains("version"), "{}", e);
+    }
+  }
+
+  #[tokio::test]
+  async fn rejects_truncated_body() {
+    let bytes = header().encode().unwrap();
+    assert!(read(&bytes[..bytes.len() - 1]).await.is_err());
+    assert!(read(&bytes[..PREFIX_SIZE - 1]).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn rejects_truncated_tlv() {
+    let mut bytes = StreamHeader::new("1-0", "example.com", 443).encode().unwrap();
+    // A TLV whose length runs past the end of the body
+    bytes.extend_from_slice(&[TLV_SOURCE_ADDRESS, 0x00, 0x10, b'x']);
+    let length = (bytes.len() - PREFIX_SIZE) as u16;
+    bytes[6..8].copy_from_slice(&length.to_be_bytes());
+    let e = read(&bytes).await.unwrap_err();
+    assert!(e.to_string().contains("truncated"), "{}", e);
+  }
+
+  #[tokio::test]
+  async fn round_trip_durations() {
+    let mut header = header();
+    header.push_duration(TLV_IDLE_TIMEOUT, Duration::from_secs(180));
+    let decoded = read(&header.encode().unwrap()).await.unwrap();
+    assert_eq!(decoded.get_duration(TLV_IDLE_TIMEOUT), Some(Duration::from_secs(180)));
+    assert_eq!(decoded.get_duration(TLV_MAX_LIFETIME), None);
+    // A value that is not 8 bytes long is ignored
+    assert_eq!(decoded.get_duration(TLV_PROXY_PROTOCOL), None);
+  }
+
+  #[test]
+  fn target_brackets_ipv6() {
+    assert_eq!(StreamHeader::new("1-0", "example.com", 80).target(), "example.com:80");
+    assert_eq!(StreamHeader::new("1-0", "::1", 80).target(), "[::1]:80");
+  }
+}
@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+// Tracks the last activity of a flow or session so that it can be expired once idle
+pub struct IdleTimer {
+  timeout: Duration,
+  last_activity: Mutex<Instant>,
+}
+
+impl IdleTimer {
+  pub fn new(timeout: Duration) -> Self {
+    IdleTimer { timeout, last_activity: Mutex::new(Instant::now()) }
+  }
+
+  pub fn touch(&self) {
+    *self.last_activity.lock().unwrap() = Instant::now();
+  }
+
+  pub async fn expired(&self) {
+    loop {
+      let deadline = *self.last_activity.lock().unwrap() + self.timeout;
+      if Instant::now() >= deadline {
+        return;
+      }
+      sleep_until(deadline).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::time::{advance, timeout};
+
+  #[tokio::test(start_paused = true)]
+  async fn touch_extends_the_deadline() {
+    let idle = IdleTimer::new(Duration::from_secs(10));
+    advance(Duration::from_secs(8)).await;
+    idle.touch();
+    assert!(timeout(Duration::from_secs(9), idle.expired()).await.is_err());
+    assert!(timeout(Duration::from_secs(2), idle.expired()).await.is_ok());
+  }
+}
@@ -0,0 +1,7 @@
+pub mod copy;
+pub mod datagram;
+pub mod header;
+pub mod idle;
+pub mod pattern;
+pub mod proxy_protocol;
+pub mod status;
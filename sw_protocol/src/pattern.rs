@@ -0,0 +1,129 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+//
+// Host and port patterns shared by the destination rules of sw_listener and sw_connector
+//
+// A host pattern is a CIDR, an IP address or a host name where "*" matches any sequence of characters.
+// A port range is a number or "from-to".
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+  Network(IpNet),
+  Name(String),
+}
+
+impl HostPattern {
+  pub fn parse(host: &str) -> Result<Self, String> {
+    if let Ok(network) = host.parse::<IpNet>() {
+      Ok(HostPattern::Network(network))
+    } else if let Ok(ip) = host.parse::<IpAddr>() {
+      Ok(HostPattern::Network(IpNet::from(ip)))
+    } else if host.contains('/') {
+      Err(format!("Invalid CIDR: {}", host))
+    } else {
+      Ok(HostPattern::Name(host.to_lowercase()))
+    }
+  }
+
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match self {
+      HostPattern::Network(network) => network.contains(ip),
+      HostPattern::Name(_) => false,
+    }
+  }
+
+  pub fn matches_name(&self, host: &str) -> bool {
+    match self {
+      HostPattern::Network(_) => false,
+      HostPattern::Name(pattern) => wildcard_match(pattern, &host.to_lowercase()),
+    }
+  }
+
+  // Function to match a host as given by a client, without resolving names
+  pub fn matches(&self, host: &str) -> bool {
+    match self {
+      HostPattern::Network(network) => host.parse::<IpAddr>().is_ok_and(|ip| network.contains(&ip)),
+      HostPattern::Name(_) => self.matches_name(host),
+    }
+  }
+}
+
+pub fn parse_port_range(range: &str) -> Result<(u16, u16), String> {
+  let parse = |port: &str| port.trim().parse::<u16>().map_err(|e| format!("Invalid port {}: {}", port, e));
+  let (from, to) = match range.split_once('-') {
+    Some((from, to)) => (parse(from)?, parse(to)?),
+    None => {
+      let port = parse(range)?;
+      (port, port)
+    }
+  };
+  if from > to {
+    return Err(format!("Invalid port range: {}", range));
+  }
+  Ok((from, to))
+}
+
+// Function to match a host name against a pattern where "*" matches any sequence of characters
+pub fn wildcard_match(pattern: &str, host: &str) -> bool {
+  let parts: Vec<&str> = pattern.split('*').collect();
+  if parts.len() == 1 {
+    return pattern == host;
+  }
+  let mut rest = match host.strip_prefix(parts[0]) {
+    Some(rest) => rest,
+    None => return false,
+  };
+  for part in &parts[1..parts.len() - 1] {
+    match rest.find(part) {
+      Some(index) => rest = &rest[index + part.len()..],
+      None => return false,
+    }
+  }
+  rest.ends_with(parts[parts.len() - 1])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn wildcards() {
+    assert!(wildcard_match("example.com", "example.com"));
+    assert!(!wildcard_match("example.com", "www.example.com"));
+    assert!(wildcard_match("*.example.com", "www.example.com"));
+    assert!(!wildcard_match("*.example.com", "example.com"));
+    assert!(wildcard_match("api-*.*.internal", "api-v2.eu.internal"));
+    assert!(!wildcard_match("api-*.*.internal", "api-v2.internal"));
+    assert!(wildcard_match("*", "anything"));
+  }
+
+  #[test]
+  fn parses_host_patterns() {
+    assert_eq!(HostPattern::parse("10.0.0.0/8").unwrap(), HostPattern::Network("10.0.0.0/8".parse().unwrap()));
+    assert_eq!(HostPattern::parse("::1").unwrap(), HostPattern::Network("::1/128".parse().unwrap()));
+    assert_eq!(HostPattern::parse("*.Example.com").unwrap(), HostPattern::Name("*.example.com".to_string()));
+    assert!(HostPattern::parse("10.0.0.0/33").is_err());
+  }
+
+  #[test]
+  fn matches_hosts_without_resolving() {
+    let network = HostPattern::parse("10.0.0.0/8").unwrap();
+    assert!(network.matches("10.1.2.3"));
+    assert!(!network.matches("192.0.2.1"));
+    assert!(!network.matches("internal"));
+    let name = HostPattern::parse("*.Internal").unwrap();
+    assert!(name.matches("db.internal"));
+    assert!(!name.matches("10.1.2.3"));
+    assert!(HostPattern::parse("*").unwrap().matches("10.1.2.3"));
+  }
+
+  #[test]
+  fn parses_port_ranges() {
+    assert_eq!(parse_port_range("443").unwrap(), (443, 443));
+    assert_eq!(parse_port_range("8000 - 8099").unwrap(), (8000, 8099));
+    assert!(parse_port_range("90-80").is_err());
+    assert!(parse_port_range("http").is_err());
+    assert!(parse_port_range("1-70000").is_err());
+  }
+}
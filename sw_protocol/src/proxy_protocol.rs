@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+//
+// PROXY protocol header written in front of a TCP connection so that the server sees the original client
+//
+// v1 is a text line ("PROXY TCP4 {src} {dst} {sport} {dport}\r\n"), v2 is the binary form
+// starting with a 12-byte signature. See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+//
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+
+pub fn encode_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+  let line = match (source, destination) {
+    (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+      format!("PROXY TCP4 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port())
+    }
+    (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+      format!("PROXY TCP6 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port())
+    }
+    _ => "PROXY UNKNOWN\r\n".to_string(),
+  };
+  line.into_bytes()
+}
+
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+  let mut bytes = V2_SIGNATURE.to_vec();
+  // version 2, PROXY command
+  bytes.push(0x21);
+  match (source, destination) {
+    (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+      bytes.push(0x11);
+      bytes.extend_from_slice(&12u16.to_be_bytes());
+      bytes.extend_from_slice(&src.ip().octets());
+      bytes.extend_from_slice(&dst.ip().octets());
+      bytes.extend_from_slice(&src.port().to_be_bytes());
+      bytes.extend_from_slice(&dst.port().to_be_bytes());
+    }
+    (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+      bytes.push(0x21);
+      bytes.extend_from_slice(&36u16.to_be_bytes());
+      bytes.extend_from_slice(&src.ip().octets());
+      bytes.extend_from_slice(&dst.ip().octets());
+      bytes.extend_from_slice(&src.port().to_be_bytes());
+      bytes.extend_from_slice(&dst.port().to_be_bytes());
+    }
+    _ => {
+      bytes.push(0x00);
+      bytes.extend_from_slice(&0u16.to_be_bytes());
+    }
+  }
+  bytes
+}
+
+pub fn encode(version: u8, source: SocketAddr, destination: SocketAddr) -> Result<Vec<u8>, Box<dyn Error>> {
+  match version {
+    1 => Ok(encode_v1(source, destination)),
+    2 => Ok(encode_v2(source, destination)),
+    _ => Err(format!("Unsupported PROXY protocol version: {}", version).into()),
+  }
+}
+
+// Function to read a v1 or v2 header, returning the source and destination addresses it carries
+//
+// None is returned for headers without addresses (UNKNOWN, LOCAL or unspecified families).
+// Nothing after the header is consumed.
+pub async fn read_header<R: AsyncRead + Unpin>(
+  reader: &mut R,
+) -> Result<Option<(SocketAddr, SocketAddr)>, Box<dyn Error>> {
+  // Both versions are at least 15 bytes long, so the first 12 bytes can be read without over-reading
+  let mut prefix = [0u8; 12];
+  reader.read_exact(&mut prefix).await?;
+  if prefix == V2_SIGNATURE {
+    read_v2(reader).await
+  } else if prefix.starts_with(b"PROXY ") {
+    read_v1(reader, &prefix).await
+  } else {
+    Err("Missing PROXY protocol header".into())
+  }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+  reader: &mut R,
+  prefix: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, Box<dyn Error>> {
+  let mut line = prefix.to_vec();
+  while !line.ends_with(b"\r\n") {
+    if line.len() >= V1_MAX_LENGTH {
+      return Err("PROXY protocol v1 header too long".into());
+    }
+    line.push(reader.read_u8().await?);
+  }
+  let line = std::str::from_utf8(&line[..line.len() - 2])?;
+  let fields: Vec<&str> = line.split(' ').collect();
+  match fields.as_slice() {
+    ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => {
+      let source = SocketAddr::new(src.parse::<IpAddr>()?, sport.parse()?);
+      let destination = SocketAddr::new(dst.parse::<IpAddr>()?, dport.parse()?);
+      Ok(Some((source, destination)))
+    }
+    ["PROXY", "UNKNOWN", ..] => Ok(None),
+    _ => Err(format!("Invalid PROXY protocol v1 header: {}", line).into()),
+  }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(SocketAddr, SocketAddr)>, Box<dyn Error>> {
+  let mut fixed = [0u8; 4];
+  reader.read_exact(&mut fixed).await?;
+  if fixed[0] >> 4 != 2 {
+    return Err(format!("Invalid PROXY protocol v2 version: {}", fixed[0] >> 4).into());
+  }
+  let mut body = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+  reader.read_exact(&mut body).await?;
+  // LOCAL command: health checks of the load balancer itself
+  if fixed[0] & 0x0f == 0x00 {
+    return Ok(None);
+  }
+  match fixed[1] >> 4 {
+    0x1 if body.len() >= 12 => {
+      let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+      let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+      let sport = u16::from_be_bytes([body[8], body[9]]);
+      let dport = u16::from_be_bytes([body[10], body[11]]);
+      Ok(Some((SocketAddr::new(src.into(), sport), SocketAddr::new(dst.into(), dport))))
+    }
+    0x2 if body.len() >= 36 => {
+      let src = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16])?);
+      let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32])?);
+      let sport = u16::from_be_bytes([body[32], body[33]]);
+      let dport = u16::from_be_bytes([body[34], body[35]]);
+      Ok(Some((SocketAddr::new(src.into(), sport), SocketAddr::new(dst.into(), dport))))
+    }
+    _ => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn read(bytes: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, Box<dyn Error>> {
+    read_header(&mut &bytes[..]).await
+  }
+
+  fn v4() -> (SocketAddr, SocketAddr) {
+    ("192.0.2.1:5000".parse().unwrap(), "198.51.100.1:443".parse().unwrap())
+  }
+
+  fn v6() -> (SocketAddr, SocketAddr) {
+    ("[2001:db8::1]:5000".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap())
+  }
+
+  #[test]
+  fn encodes_v1() {
+    let (src, dst) = v4();
+    assert_eq!(encode_v1(src, dst), b"PROXY TCP4 192.0.2.1 198.51.100.1 5000 443\r\n");
+    let (src, dst) = v6();
+    assert_eq!(encode_v1(src, dst), b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 443\r\n");
+    assert_eq!(encode_v1(v4().0, v6().1), b"PROXY UNKNOWN\r\n");
+  }
+
+  #[tokio::test]
+  async fn round_trips_both_versions() {
+    for (src, dst) in [v4(), v6()] {
+      for version in [1, 2] {
+        let bytes = encode(version, src, dst).unwrap();
+        assert_eq!(read(&bytes).await.unwrap(), Some((src, dst)), "v{}", version);
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn leaves_data_after_header() {
+    for version in [1, 2] {
+      let (src, dst) = v6();
+      let mut bytes = encode(version, src, dst).unwrap();
+      bytes.extend_from_slice(b"GET / HTTP/1.1");
+      let mut reader = &bytes[..];
+      read_header(&mut reader).await.unwrap();
+      assert_eq!(reader, b"GET / HTTP/1.1");
+    }
+  }
+
+  #[tokio::test]
+  async fn returns_none_without_addresses() {
+    assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    let mut local = V2_SIGNATURE.to_vec();
+    // version 2, LOCAL command, unspecified family, no addresses
+    local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    assert_eq!(read(&local).await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn v2_local_skips_addresses() {
+    let (src, dst) = v4();
+    let mut bytes = encode_v2(src, dst);
+    bytes[12] = 0x20;
+    bytes.push(b'x');
+    let mut reader = &bytes[..];
+    assert_eq!(read_header(&mut reader).await.unwrap(), None);
+    assert_eq!(reader, b"x");
+  }
+
+  #[tokio::test]
+  async fn rejects_v1_over_length() {
+    let mut line = b"PROXY TCP4 ".to_vec();
+    line.resize(V1_MAX_LENGTH + 10, b'1');
+    line.extend_from_slice(b"\r\n");
+    let e = read(&line).await.unwrap_err();
+    assert!(e.to_string().contains("too long"), "{}", e);
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_headers() {
+    assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+    assert!(read(b"PROXY TCP4 a b c d\r\n").await.is_err());
+    let mut bytes = encode_v2(v4().0, v4().1);
+    bytes[12] = 0x11;
+    assert!(read(&bytes).await.is_err());
+    assert!(encode(3, v4().0, v4().1).is_err());
+  }
+}
@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//
+// Status frame sent back by sw_connector once it has tried to reach the target
+//
+// +--------+--------+---------+
+// | status | length | message |
+// | 1      | 2      | length  |
+// +--------+--------+---------+
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ConnectStatus {
+  Success = 0,
+  Refused = 1,
+  Timeout = 2,
+  DnsFailure = 3,
+  DeniedByPolicy = 4,
+  Unreachable = 5,
+  Error = 255,
+}
+
+impl ConnectStatus {
+  pub fn from_u8(value: u8) -> Self {
+    match value {
+      0 => ConnectStatus::Success,
+      1 => ConnectStatus::Refused,
+      2 => ConnectStatus::Timeout,
+      3 => ConnectStatus::DnsFailure,
+      4 => ConnectStatus::DeniedByPolicy,
+      5 => ConnectStatus::Unreachable,
+      _ => ConnectStatus::Error,
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ConnectStatus::Success => "success",
+      ConnectStatus::Refused => "refused",
+      ConnectStatus::Timeout => "timeout",
+      ConnectStatus::DnsFailure => "dns_failure",
+      ConnectStatus::DeniedByPolicy => "denied_by_policy",
+      ConnectStatus::Unreachable => "unreachable",
+      ConnectStatus::Error => "error",
+    }
+  }
+}
+
+impl fmt::Display for ConnectStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusFrame {
+  pub status: ConnectStatus,
+  pub message: String,
+}
+
+impl StatusFrame {
+  pub fn new(status: ConnectStatus, message: &str) -> Self {
+    StatusFrame { status, message: message.to_string() }
+  }
+
+  pub fn is_success(&self) -> bool {
+    self.status == ConnectStatus::Success
+  }
+
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+    let message = self.message.as_bytes();
+    let length = message.len().min(u16::MAX as usize);
+    let mut bytes = Vec::with_capacity(3 + length);
+    bytes.push(self.status as u8);
+    bytes.extend_from_slice(&(length as u16).to_be_bytes());
+    bytes.extend_from_slice(&message[..length]);
+    writer.write_all(&bytes).await?;
+    Ok(())
+  }
+
+  pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+    let mut prefix = [0u8; 3];
+    reader.read_exact(&mut prefix).await?;
+    let length = u16::from_be_bytes([prefix[1], prefix[2]]) as usize;
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message).await?;
+    Ok(StatusFrame { status: ConnectStatus::from_u8(prefix[0]), message: String::from_utf8_lossy(&message).to_string() })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn round_trip() {
+    for status in [ConnectStatus::Success, ConnectStatus::DeniedByPolicy, ConnectStatus::Error] {
+      let frame = StatusFrame::new(status, "message");
+      let mut bytes = Vec::new();
+      frame.write_to(&mut bytes).await.unwrap();
+      assert_eq!(StatusFrame::read_from(&mut &bytes[..]).await.unwrap(), frame);
+    }
+  }
+
+  #[test]
+  fn unknown_status_is_error() {
+    assert_eq!(ConnectStatus::from_u8(5), ConnectStatus::Unreachable);
+    assert_eq!(ConnectStatus::from_u8(42), ConnectStatus::Error);
+  }
+
+  #[tokio::test]
+  async fn rejects_truncated_message() {
+    let bytes = [0u8, 0x00, 0x05, b'a'];
+    assert!(StatusFrame::read_from(&mut &bytes[..]).await.is_err());
+  }
+}